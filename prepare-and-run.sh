@@ -6,7 +6,7 @@ ulimit -n 32768
 
 for port in 20000 20001 20002 20003 20004
 do
-    PID=$(lsof -ti tcp:"$port" | xargs)
+    PID=$(lsof -ti tcp:"$port" -i udp:"$port" | xargs)
     if [ ! -z "$PID" ]
     then
         kill $PID
@@ -15,9 +15,11 @@ done
 
 cargo build --release --manifest-path ./testserver/Cargo.toml
 
-cargo build --release --manifest-path ./tokio_tcp_proxy/Cargo.toml
+cargo build --release --features no-logging --manifest-path ./tokio_tcp_proxy/Cargo.toml
 
-cargo build --release --manifest-path ./std_tcp_proxy/Cargo.toml
+cargo build --release --features no-logging --manifest-path ./std_tcp_proxy/Cargo.toml
+
+cargo build --release --manifest-path ./tokio_udp_proxy/Cargo.toml
 
 go build -o go_tcp_proxy/go_tcp_proxy go_tcp_proxy/main.go
 
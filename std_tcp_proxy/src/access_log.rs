@@ -0,0 +1,128 @@
+use std::{
+    fmt,
+    fs::OpenOptions,
+    io::{self, LineWriter, Write},
+    path::Path,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+/// One end of a proxied connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    Client,
+    Upstream,
+}
+
+impl Side {
+    pub fn other(self) -> Side {
+        match self {
+            Side::Client => Side::Upstream,
+            Side::Upstream => Side::Client,
+        }
+    }
+
+    /// The direction of the bytes read from this side
+    pub fn direction(self) -> &'static str {
+        match self {
+            Side::Client => "up",
+            Side::Upstream => "down",
+        }
+    }
+}
+
+impl fmt::Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Side::Client => write!(f, "client"),
+            Side::Upstream => write!(f, "upstream"),
+        }
+    }
+}
+
+/// Why a direction, or a whole connection, stopped
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseReason {
+    /// This side closed its end first
+    Eof(Side),
+    ReadError(Side, io::ErrorKind),
+    WriteError(Side, io::ErrorKind),
+    /// The transforms rejected what this side sent
+    TransformError(Side, io::ErrorKind),
+    /// `std::io::copy` does not tell which side failed
+    Error(io::ErrorKind),
+    ConnectFailed(io::ErrorKind),
+    /// The upstream's circuit breaker is open, so the proxy did not connect
+    CircuitOpen,
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseReason::Eof(side) => write!(f, "{}_closed", side),
+            CloseReason::ReadError(side, kind) => write!(f, "{}_read_error:{:?}", side, kind),
+            CloseReason::WriteError(side, kind) => write!(f, "{}_write_error:{:?}", side, kind),
+            CloseReason::TransformError(side, kind) => {
+                write!(f, "{}_transform_error:{:?}", side, kind)
+            }
+            CloseReason::Error(kind) => write!(f, "error:{:?}", kind),
+            CloseReason::ConnectFailed(kind) => write!(f, "connect_failed:{:?}", kind),
+            CloseReason::CircuitOpen => write!(f, "circuit_open"),
+        }
+    }
+}
+
+/// One access-log line. Both Rust TCP proxies write the same format:
+///
+/// `start=<RFC 3339> duration_ms=<n> client=<addr> upstream=<addr> bytes_up=<n> bytes_down=<n> close=<reason>`
+pub struct Entry<'a> {
+    pub start: SystemTime,
+    pub duration: Duration,
+    pub client: &'a str,
+    pub upstream: &'a str,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub close: CloseReason,
+}
+
+impl fmt::Display for Entry<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "start={} duration_ms={} client={} upstream={} bytes_up={} bytes_down={} close={}",
+            humantime::format_rfc3339_millis(self.start),
+            self.duration.as_millis(),
+            self.client,
+            self.upstream,
+            self.bytes_up,
+            self.bytes_down,
+            self.close,
+        )
+    }
+}
+
+/// Where access-log lines go, if anywhere
+pub struct AccessLog {
+    out: Option<Mutex<LineWriter<Box<dyn Write + Send>>>>,
+}
+
+impl AccessLog {
+    /// Appends to `path`, or writes to stdout for `-`. Without a path, logging
+    /// an entry does nothing.
+    pub fn open(path: Option<&Path>) -> io::Result<AccessLog> {
+        let out: Box<dyn Write + Send> = match path {
+            None => return Ok(AccessLog { out: None }),
+            Some(path) if path == Path::new("-") => Box::new(io::stdout()),
+            Some(path) => Box::new(OpenOptions::new().create(true).append(true).open(path)?),
+        };
+        Ok(AccessLog {
+            out: Some(Mutex::new(LineWriter::new(out))),
+        })
+    }
+
+    pub fn log(&self, entry: &Entry) {
+        if let Some(out) = &self.out {
+            let _ = writeln!(out.lock().unwrap(), "{}", entry);
+        }
+    }
+}
@@ -0,0 +1,12 @@
+use tracing_subscriber::filter::LevelFilter;
+
+/// Sets up the global subscriber. Building with the `no-logging` feature
+/// compiles every event and span below `off` away, whatever `level` says.
+pub fn init(level: LevelFilter, format: &str) {
+    let builder = tracing_subscriber::fmt().with_max_level(level);
+    if format == "text" {
+        builder.init();
+    } else {
+        builder.json().init();
+    }
+}
@@ -1,87 +1,426 @@
+use access_log::{AccessLog, CloseReason, Entry, Side};
+use breaker::{Breaker, Thresholds};
 use clap::Clap;
-use socket2::{Domain, Socket, Type};
+use net::{Address, Cidrs, Connection, Listener, Stream};
 use std::{
-    io::{Read, Write},
-    net::{SocketAddr, TcpListener, TcpStream},
+    io::{self, Read, Write},
+    net::IpAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc,
+    },
+    time::{Duration, Instant, SystemTime},
 };
+use tap::{Tap, Tapped};
+use tracing::{debug, info, info_span, warn, Span};
+use tracing_subscriber::filter::LevelFilter;
+use transform::{Chain, Mode, Transform, Transforms};
 
 #[macro_use]
 extern crate lazy_static;
 
+mod access_log;
+mod breaker;
+mod logging;
+mod net;
+mod tap;
+mod transform;
+mod zero_copy;
+
 /// A simple TCP proxy
 #[derive(Clap, Debug)]
 struct Args {
-    /// The address to listen on
+    /// The address to listen on, `host:port` or `unix:/path`
     #[clap(short, long, default_value = "127.0.0.1:20000")]
-    pub listen: String,
-    /// The address to connect to
+    pub listen: Address,
+    /// The address to connect to, `host:port` or `unix:/path`
     #[clap(short, long, default_value = "127.0.0.1:20002")]
-    pub upstream: String,
+    pub upstream: Address,
     /// Whether to use std copy util or custom implementation
     #[clap(short, long)]
     pub std_copy: bool,
+    /// Move bytes with `splice(2)` through a pipe of `--buf-size` bytes, so
+    /// they never enter userspace
+    #[clap(long, conflicts_with = "std-copy")]
+    pub splice: bool,
+    /// Send with `MSG_ZEROCOPY` from `--buf-size` buffers, reaping the
+    /// completions before reusing one. Only TCP sockets support it; other
+    /// upstreams or clients use the custom implementation.
+    #[clap(long, conflicts_with_all = &["std-copy", "splice"])]
+    pub msg_zerocopy: bool,
     /// Buffer size for custom implementation
     #[clap(short, long, default_value = "1024")]
     pub buf_size: usize,
+    /// Set IPV6_V6ONLY on an IPv6 listen address; `false` makes `[::]` dual-stack
+    #[clap(long)]
+    pub ipv6_only: Option<bool>,
+    /// Only accept clients from these networks, as a comma-separated list
+    /// such as `10.0.0.0/8,fd00::/8`. Without it, every client is accepted.
+    #[clap(long)]
+    pub allow_clients: Option<Cidrs>,
+    /// Turn away clients from these networks, even when they are allowed
+    #[clap(long)]
+    pub deny_clients: Option<Cidrs>,
+    /// Consecutive failed connects to the upstream that open the circuit
+    /// breaker, after which clients fail fast instead of connecting; 0 never
+    /// opens it on a streak
+    #[clap(long, default_value = "0")]
+    pub breaker_failures: u32,
+    /// The share of the last `--breaker-window` connects that have to fail to
+    /// open the circuit breaker, such as 0.5; 0 never opens it on a rate
+    #[clap(long, default_value = "0")]
+    pub breaker_failure_rate: f64,
+    /// How many recent connects `--breaker-failure-rate` looks at
+    #[clap(long, default_value = "20")]
+    pub breaker_window: usize,
+    /// Seconds an open circuit breaker fails clients fast before it lets one
+    /// connect through to probe the upstream
+    #[clap(long, default_value = "10")]
+    pub breaker_open_for: u64,
+    /// Seconds between stats log lines, with the open connections, rejected
+    /// clients and the circuit breaker's state; 0 never logs them
+    #[clap(long, default_value = "0")]
+    pub stats_interval: u64,
+    /// Transforms to apply to every chunk, as a comma-separated list of gzip,
+    /// zstd, xor:<key> and checksum. They always use the custom
+    /// implementation.
+    #[clap(long)]
+    pub transform: Option<Transforms>,
+    /// `encode` what the client sends and decode what the upstream sends, or
+    /// the other way around with `decode`, for the proxy on the far end
+    #[clap(long, default_value = "encode", possible_values = &["encode", "decode"])]
+    pub transform_mode: Mode,
+    /// A file to append one line to per closed connection, or `-` for stdout
+    #[clap(long)]
+    pub access_log: Option<PathBuf>,
+    /// A file to capture every chunk read in either direction to, for
+    /// `tap_replay`. Off by default, as it costs a copy of all traffic.
+    #[clap(long)]
+    pub tap: Option<PathBuf>,
+    /// The most verbose log level to emit: off, error, warn, info, debug or
+    /// trace. Build with the `no-logging` feature to compile logging out.
+    #[clap(long, default_value = "info")]
+    pub log_level: LevelFilter,
+    /// Log as JSON lines or as human-readable text
+    #[clap(long, default_value = "json", possible_values = &["json", "text"])]
+    pub log_format: String,
 }
 
 lazy_static! {
     static ref ARGS: Args = Args::parse();
+    static ref ACCESS_LOG: AccessLog =
+        AccessLog::open(ARGS.access_log.as_deref()).expect("Failed to open access log");
+    static ref TAP: Tap = Tap::open(ARGS.tap.as_deref()).expect("Failed to open tap file");
+    static ref BREAKER: Option<Breaker> = breaker();
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+/// Clients turned away by the allow and deny lists
+static REJECTED: AtomicU64 = AtomicU64::new(0);
+/// Connections being proxied
+static OPEN: AtomicU64 = AtomicU64::new(0);
+
+/// What the logs need to know about an accepted connection
+struct Accepted {
+    id: u64,
+    span: Span,
+    start: SystemTime,
+    started: Instant,
+    client: String,
+}
+
+impl Accepted {
+    fn close(&self, bytes_up: u64, bytes_down: u64, close: CloseReason) {
+        info!(parent: &self.span, bytes_up, bytes_down, reason = %close, "connection closed");
+        ACCESS_LOG.log(&Entry {
+            start: self.start,
+            duration: self.started.elapsed(),
+            client: &self.client,
+            upstream: &ARGS.upstream.to_string(),
+            bytes_up,
+            bytes_down,
+            close,
+        });
+    }
 }
 
 fn main() {
-    println!(
-        "std tcp server:: listen={}, upstream={}, std_copy={}, buf_size={}",
-        &ARGS.listen, &ARGS.upstream, &ARGS.std_copy, &ARGS.buf_size,
+    logging::init(ARGS.log_level, &ARGS.log_format);
+    lazy_static::initialize(&ACCESS_LOG);
+    lazy_static::initialize(&TAP);
+    lazy_static::initialize(&BREAKER);
+    info!(
+        listen = %ARGS.listen,
+        upstream = %ARGS.upstream,
+        std_copy = ARGS.std_copy,
+        splice = ARGS.splice,
+        msg_zerocopy = ARGS.msg_zerocopy,
+        buf_size = ARGS.buf_size,
+        ipv6_only = ?ARGS.ipv6_only,
+        allow_clients = ?ARGS.allow_clients,
+        deny_clients = ?ARGS.deny_clients,
+        breaker = ?BREAKER.as_ref().map(|breaker| breaker.thresholds()),
+        transform = ?ARGS.transform,
+        transform_mode = %ARGS.transform_mode,
+        "std tcp server started"
     );
-    let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
-    let address: SocketAddr = ARGS.listen.parse().unwrap();
-    let _ = socket.bind(&address.into());
-    let _ = socket.set_reuse_address(true);
-    let _ = socket.listen(128);
-    let listener: TcpListener = socket.into();
+    if ARGS.stats_interval > 0 {
+        std::thread::spawn(|| log_stats(Duration::from_secs(ARGS.stats_interval)));
+    }
+    let listener =
+        Listener::bind(&ARGS.listen, ARGS.ipv6_only).expect("Failed to bind to listen address");
 
     loop {
-        let (socket, _) = listener.accept().unwrap();
-
-        match TcpStream::connect(&ARGS.upstream) {
-            Ok(target) => {
-                std::thread::spawn(move || {
-                    let mut cr = socket.try_clone().unwrap();
-                    let mut cw = socket;
-                    let mut ur = target.try_clone().unwrap();
-                    let mut uw = target;
-
-                    if ARGS.std_copy {
-                        std::thread::spawn(move || {
-                            let _ = std::io::copy(&mut cr, &mut uw);
-                        });
-                        std::thread::spawn(move || {
-                            let _ = std::io::copy(&mut ur, &mut cw);
-                        });
-                    } else {
-                        std::thread::spawn(move || {
-                            let _ = forward(cr, uw);
-                        });
-                        std::thread::spawn(move || {
-                            let _ = forward(ur, cw);
-                        });
-                    }
-                });
+        let (client, peer) = listener.accept().unwrap();
+        // Turned away before connecting upstream for them
+        if let Some(peer) = peer.filter(|peer| !admits(peer.ip())) {
+            let rejected = REJECTED.fetch_add(1, Ordering::Relaxed) + 1;
+            warn!(%peer, rejected, "client rejected");
+            continue;
+        }
+        let client_addr = peer.map_or_else(|| "unix".to_owned(), |peer| peer.to_string());
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let accepted = Accepted {
+            id,
+            span: info_span!(
+                "connection",
+                id,
+                client = %client_addr,
+                upstream = %ARGS.upstream,
+            ),
+            start: SystemTime::now(),
+            started: Instant::now(),
+            client: client_addr,
+        };
+
+        // The connect blocks the accept loop, so an upstream that keeps
+        // failing is not tried for every client
+        if let Some(breaker) = BREAKER.as_ref().filter(|breaker| !breaker.allow()) {
+            let (state, failed_fast) = (breaker.state(), breaker.failed_fast());
+            warn!(parent: &accepted.span, %state, failed_fast, "circuit breaker open, failing fast");
+            accepted.close(0, 0, CloseReason::CircuitOpen);
+            continue;
+        }
+        let connected = Connection::connect(&ARGS.upstream);
+        if let Some(breaker) = BREAKER.as_ref() {
+            breaker.record(connected.is_ok());
+        }
+        match connected {
+            Ok(target) => match (client, target) {
+                (Connection::Tcp(c), Connection::Tcp(u)) => proxy(c, u, accepted),
+                (Connection::Tcp(c), Connection::Unix(u)) => proxy(c, u, accepted),
+                (Connection::Unix(c), Connection::Tcp(u)) => proxy(c, u, accepted),
+                (Connection::Unix(c), Connection::Unix(u)) => proxy(c, u, accepted),
+            },
+            Err(err) => {
+                warn!(parent: &accepted.span, error = %err, "failed to connect to upstream");
+                accepted.close(0, 0, CloseReason::ConnectFailed(err.kind()));
             }
-            Err(_) => {
-                println!("Failed to connect to upstream.");
+        }
+    }
+}
+
+/// The upstream's circuit breaker, when there are thresholds that open it
+fn breaker() -> Option<Breaker> {
+    let thresholds = Thresholds {
+        failures: ARGS.breaker_failures,
+        failure_rate: ARGS.breaker_failure_rate,
+        window: ARGS.breaker_window,
+        open_for: Duration::from_secs(ARGS.breaker_open_for),
+    };
+    let rate = thresholds.failure_rate;
+    assert!(
+        (0.0..=1.0).contains(&rate) && (rate == 0.0 || thresholds.window > 0),
+        "A breaker failure rate is between 0 and 1, over a window of at least one connect"
+    );
+    Some(Breaker::new(ARGS.upstream.to_string(), thresholds)).filter(|_| thresholds.enabled())
+}
+
+/// Logs the same counts as the tokio proxy's `/stats` every `interval`
+fn log_stats(interval: Duration) {
+    loop {
+        std::thread::sleep(interval);
+        let open_connections = OPEN.load(Ordering::Relaxed);
+        let rejected_clients = REJECTED.load(Ordering::Relaxed);
+        match BREAKER.as_ref() {
+            Some(breaker) => {
+                let (state, failed_fast) = (breaker.state(), breaker.failed_fast());
+                info!(
+                    open_connections,
+                    rejected_clients,
+                    breaker = %state,
+                    failed_fast,
+                    "stats"
+                );
             }
+            None => info!(open_connections, rejected_clients, "stats"),
         }
     }
 }
 
-fn forward(mut read: TcpStream, mut write: TcpStream) {
+/// Whether a client may connect: it must not be on the deny list, and must be
+/// on the allow list if there is one
+fn admits(client: IpAddr) -> bool {
+    let denied = ARGS
+        .deny_clients
+        .as_ref()
+        .is_some_and(|denied| denied.contains(client));
+    let allowed = ARGS
+        .allow_clients
+        .as_ref()
+        .is_none_or(|allowed| allowed.contains(client));
+    allowed && !denied
+}
+
+fn proxy<C: Stream, U: Stream>(socket: C, target: U, accepted: Accepted) {
+    OPEN.fetch_add(1, Ordering::Relaxed);
+    std::thread::spawn(move || {
+        let cr = socket.try_clone().unwrap();
+        let cw = socket;
+        let ur = target.try_clone().unwrap();
+        let uw = target;
+
+        let (done, finished) = mpsc::channel();
+        spawn_direction(Side::Client, cr, uw, &accepted, done.clone());
+        spawn_direction(Side::Upstream, ur, cw, &accepted, done);
+
+        // Whichever direction stops first explains why the connection closed
+        let (mut bytes_up, mut bytes_down) = (0, 0);
+        let mut close = None;
+        for (from, reason, bytes) in finished {
+            match from {
+                Side::Client => bytes_up = bytes,
+                Side::Upstream => bytes_down = bytes,
+            }
+            close.get_or_insert(reason);
+        }
+        if let Some(close) = close {
+            accepted.close(bytes_up, bytes_down, close);
+        }
+        OPEN.fetch_sub(1, Ordering::Relaxed);
+    });
+}
+
+/// Forwards the bytes read `from` one side on their own thread, in a span
+/// under the connection's, and reports how it ended on `done`
+fn spawn_direction<R: Stream, W: Stream>(
+    from: Side,
+    mut read: R,
+    mut write: W,
+    accepted: &Accepted,
+    done: mpsc::Sender<(Side, CloseReason, u64)>,
+) {
+    let span = info_span!(parent: &accepted.span, "direction", direction = from.direction());
+    let id = accepted.id;
+    std::thread::spawn(move || {
+        let _entered = span.enter();
+        // The tap and transforms need the bytes in userspace
+        let plain = !TAP.is_enabled() && ARGS.transform.as_ref().is_none_or(|t| t.0.is_empty());
+        let (reason, bytes) = if plain && ARGS.splice {
+            zero_copy::splice(&read, &write, from, ARGS.buf_size)
+        } else if plain && ARGS.msg_zerocopy && zerocopy_enabled(&write) {
+            zero_copy::send_zerocopy(&mut read, &write, from, ARGS.buf_size)
+        } else if TAP.is_enabled() {
+            copy(&mut Tapped::new(read, &TAP, id, from), &mut write, from)
+        } else {
+            copy(&mut read, &mut write, from)
+        };
+        // Pass the EOF on, so the other direction can finish too
+        let _ = write.shutdown_write();
+        debug!(bytes, reason = %reason, "direction closed");
+        let _ = done.send((from, reason, bytes));
+    });
+}
+
+/// Whether `MSG_ZEROCOPY` sends work on the socket, which they only do on TCP
+fn zerocopy_enabled<W: Stream>(write: &W) -> bool {
+    match zero_copy::enable_zerocopy(write) {
+        Ok(()) => true,
+        Err(err) => {
+            debug!(error = %err, "MSG_ZEROCOPY unavailable, copying instead");
+            false
+        }
+    }
+}
+
+fn copy<R: Read, W: Write>(read: &mut R, write: &mut W, from: Side) -> (CloseReason, u64) {
+    let transform = ARGS
+        .transform
+        .as_ref()
+        .and_then(|transforms| Chain::new(transforms, ARGS.transform_mode, from));
+    if ARGS.std_copy && transform.is_none() {
+        let mut write = Counted {
+            inner: write,
+            bytes: 0,
+        };
+        match std::io::copy(read, &mut write) {
+            Ok(_) => (CloseReason::Eof(from), write.bytes),
+            Err(err) => (CloseReason::Error(err.kind()), write.bytes),
+        }
+    } else {
+        forward(read, write, from, transform)
+    }
+}
+
+/// Counts the bytes written through it, as `std::io::copy` only returns its
+/// count when it succeeds
+struct Counted<'a, W> {
+    inner: &'a mut W,
+    bytes: u64,
+}
+
+impl<W: Write> Write for Counted<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.bytes += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Copies the bytes read `from` one side to the other until EOF or an error,
+/// returning why it stopped and the byte count. With transforms, every chunk
+/// goes through them on the way.
+fn forward<R: Read, W: Write>(
+    read: &mut R,
+    write: &mut W,
+    from: Side,
+    mut transform: Option<Chain>,
+) -> (CloseReason, u64) {
     let buf_size = ARGS.buf_size;
     let mut buf: Vec<u8> = vec![0; buf_size];
-    while let Ok(n) = read.read(&mut buf) {
-        if n == 0 || write.write_all(&buf[..n]).is_err() {
-            break;
+    let mut transformed: Vec<u8> = Vec::new();
+    let mut bytes = 0;
+    loop {
+        let n = match read.read(&mut buf) {
+            Ok(n) => n,
+            Err(err) => return (CloseReason::ReadError(from, err.kind()), bytes),
+        };
+        let chunk = match &mut transform {
+            Some(transform) => {
+                transformed.clear();
+                let applied = if n == 0 {
+                    transform.finish(&mut transformed)
+                } else {
+                    transform.apply(&buf[..n], &mut transformed)
+                };
+                if let Err(err) = applied {
+                    return (CloseReason::TransformError(from, err.kind()), bytes);
+                }
+                &transformed[..]
+            }
+            None => &buf[..n],
+        };
+        if let Err(err) = write.write_all(chunk) {
+            return (CloseReason::WriteError(from.other(), err.kind()), bytes);
+        }
+        if n == 0 {
+            return (CloseReason::Eof(from), bytes);
         }
+        bytes += n as u64;
     }
 }
@@ -0,0 +1,290 @@
+use socket2::{Domain, Socket, Type};
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    os::unix::{
+        fs::FileTypeExt,
+        io::AsRawFd,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+const UNIX_PREFIX: &str = "unix:";
+
+/// A listen or upstream address: `host:port` for TCP or `unix:/path` for a
+/// Unix domain socket
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Address {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix(UNIX_PREFIX) {
+            Some("") => Err(format!("Missing socket path in {}", s)),
+            Some(path) => Ok(Address::Unix(PathBuf::from(path))),
+            None => Ok(Address::Tcp(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Tcp(addr) => write!(f, "{}", addr),
+            Address::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+/// An IP network such as `10.0.0.0/8` or `fd00::/8`. A bare address is a
+/// network of one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Dual-stack listeners see IPv4 clients as IPv4-mapped IPv6 addresses
+        let (network, ip, bits) = match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                (u32::from(network) as u128, u32::from(ip) as u128, 32)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => (u128::from(network), u128::from(ip), 128),
+            _ => return false,
+        };
+        (network ^ ip)
+            .checked_shr(bits - self.prefix as u32)
+            .unwrap_or(0)
+            == 0
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let invalid = || {
+            format!(
+                "Expected an address or a network such as 10.0.0.0/8, got {}",
+                s
+            )
+        };
+        let (network, prefix) = match s.split_once('/') {
+            Some((network, prefix)) => (network, Some(prefix)),
+            None => (s, None),
+        };
+        let network: IpAddr = network.parse().map_err(|_| invalid())?;
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|&prefix| prefix <= bits)
+                .ok_or_else(invalid)?,
+            None => bits,
+        };
+        Ok(Cidr { network, prefix })
+    }
+}
+
+/// A comma-separated list of networks
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cidrs(Vec<Cidr>);
+
+impl Cidrs {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+impl FromStr for Cidrs {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidrs, String> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map(Cidrs)
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Binds the listener. `ipv6_only` sets `IPV6_V6ONLY` on IPv6 addresses,
+    /// so `[::]` can serve both families, and is left to the OS when `None`.
+    pub fn bind(address: &Address, ipv6_only: Option<bool>) -> io::Result<Listener> {
+        match address {
+            Address::Tcp(addr) => {
+                let address: SocketAddr = addr
+                    .parse()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+                if let (true, Some(only_v6)) = (address.is_ipv6(), ipv6_only) {
+                    socket.set_only_v6(only_v6)?;
+                }
+                socket.set_reuse_address(true)?;
+                socket.bind(&address.into())?;
+                socket.listen(128)?;
+                Ok(Listener::Tcp(socket.into()))
+            }
+            Address::Unix(path) => {
+                remove_stale_socket(path)?;
+                UnixListener::bind(path).map(Listener::Unix)
+            }
+        }
+    }
+
+    /// Returns the connection and, for TCP, the client's address
+    pub fn accept(&self) -> io::Result<(Connection, Option<SocketAddr>)> {
+        match self {
+            Listener::Tcp(listener) => listener
+                .accept()
+                .map(|(stream, peer)| (Connection::Tcp(stream), Some(peer))),
+            Listener::Unix(listener) => listener
+                .accept()
+                .map(|(stream, _)| (Connection::Unix(stream), None)),
+        }
+    }
+}
+
+/// A socket file left behind by a previous run would make the bind fail
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+/// An accepted or connected socket of either kind. The forwarding code is
+/// generic over `Stream` rather than using this enum, so that `std::io::copy`
+/// still sees the concrete socket types it can specialize for.
+pub enum Connection {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Connection {
+    pub fn connect(address: &Address) -> io::Result<Connection> {
+        match address {
+            Address::Tcp(addr) => TcpStream::connect(addr).map(Connection::Tcp),
+            Address::Unix(path) => UnixStream::connect(path).map(Connection::Unix),
+        }
+    }
+}
+
+pub trait Stream: Read + Write + AsRawFd + Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+    /// Sends EOF to the peer, since dropping one clone keeps the socket open
+    fn shutdown_write(&self) -> io::Result<()>;
+}
+
+impl Stream for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+impl Stream for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn v4_networks() {
+        let network = cidr("10.1.0.0/16");
+        assert!(network.contains(ip("10.1.0.0")));
+        assert!(network.contains(ip("10.1.255.255")));
+        assert!(!network.contains(ip("10.2.0.1")));
+        assert!(!network.contains(ip("::1")));
+        // Host bits in the network do not matter
+        assert!(cidr("10.1.2.3/16").contains(ip("10.1.200.1")));
+    }
+
+    #[test]
+    fn v6_networks() {
+        let network = cidr("fd00::/8");
+        assert!(network.contains(ip("fd12:3456::1")));
+        assert!(!network.contains(ip("fe80::1")));
+        assert!(!network.contains(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn mapped_clients_match_v4_networks() {
+        assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.0.0.1")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("::ffff:11.0.0.1")));
+        assert!(!cidr("::/0").contains(ip("::ffff:10.0.0.1")));
+    }
+
+    #[test]
+    fn whole_and_single_address_prefixes() {
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+        assert!(cidr("192.0.2.1/32").contains(ip("192.0.2.1")));
+        assert!(!cidr("192.0.2.1/32").contains(ip("192.0.2.2")));
+        assert!(cidr("2001:db8::1/128").contains(ip("2001:db8::1")));
+        assert!(!cidr("2001:db8::1/128").contains(ip("2001:db8::2")));
+        assert_eq!(cidr("192.0.2.1"), cidr("192.0.2.1/32"));
+        assert_eq!(cidr("2001:db8::1"), cidr("2001:db8::1/128"));
+    }
+
+    #[test]
+    fn rejects_malformed_networks() {
+        for s in &[
+            "",
+            "10.0.0.0/",
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0.0/-1",
+            "10.0.0/8",
+            "example.com/8",
+            "10.0.0.0/8/8",
+        ] {
+            assert!(s.parse::<Cidr>().is_err(), "{} parsed", s);
+        }
+    }
+
+    #[test]
+    fn lists_of_networks() {
+        let cidrs: Cidrs = " 10.0.0.0/8, ,fd00::/8 ".parse().unwrap();
+        assert!(cidrs.contains(ip("10.9.9.9")));
+        assert!(cidrs.contains(ip("fd00::9")));
+        assert!(!cidrs.contains(ip("192.0.2.1")));
+        assert!("10.0.0.0/8,nope".parse::<Cidrs>().is_err());
+    }
+}
@@ -0,0 +1,467 @@
+use crate::access_log::Side;
+use flate2::{
+    write::{GzDecoder, GzEncoder},
+    Compression,
+};
+use std::{
+    convert::TryInto,
+    fmt,
+    io::{self, Write},
+    mem,
+    str::FromStr,
+};
+use zstd::stream::{raw, zio};
+
+/// Zstandard's fastest regular level, to match `Compression::fast` for gzip
+const ZSTD_LEVEL: i32 = 1;
+
+/// Largest frame the checksum transform accepts, so a corrupt length cannot
+/// make the other end buffer forever
+const MAX_FRAME: usize = 16 * 1024 * 1024;
+
+/// Changes the bytes of one direction on their way through `forward`. Most
+/// transforms come in pairs, so two cooperating proxies can encode on one end
+/// and decode on the other.
+pub trait Transform: Send {
+    /// Appends what `chunk` turns into to `out`, which may be nothing while
+    /// the transform waits for more
+    fn apply(&mut self, chunk: &[u8], out: &mut Vec<u8>) -> io::Result<()>;
+
+    /// Appends whatever is still held back, once the direction reaches EOF
+    fn finish(&mut self, _out: &mut Vec<u8>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// One built-in transform, as named on the command line
+#[derive(Clone, Debug, PartialEq)]
+pub enum Spec {
+    /// A gzip stream, flushed after every chunk
+    Gzip,
+    /// A Zstandard stream, flushed after every chunk
+    Zstd,
+    /// XOR with a repeating key, the same both ways
+    Xor(Vec<u8>),
+    /// Frames every chunk with its length and CRC32, and checks them on the
+    /// way out
+    Checksum,
+}
+
+impl FromStr for Spec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Spec, String> {
+        match s {
+            "gzip" => Ok(Spec::Gzip),
+            "zstd" => Ok(Spec::Zstd),
+            "checksum" => Ok(Spec::Checksum),
+            _ => match s.strip_prefix("xor:") {
+                Some(key) if !key.is_empty() => Ok(Spec::Xor(key.as_bytes().to_vec())),
+                Some(_) => Err("The XOR key is empty".to_owned()),
+                None => Err(format!(
+                    "Unknown transform {}, expected gzip, zstd, xor:<key> or checksum",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// A comma-separated list of transforms, applied in order when encoding and in
+/// reverse when decoding
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Transforms(pub Vec<Spec>);
+
+impl FromStr for Transforms {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Transforms, String> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map(Transforms)
+    }
+}
+
+/// Which end of a pair of cooperating proxies this one is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Encodes what the client sends, and decodes what the upstream sends
+    Encode,
+    /// Decodes what the client sends, and encodes what the upstream sends
+    Decode,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Mode, String> {
+        match s {
+            "encode" => Ok(Mode::Encode),
+            "decode" => Ok(Mode::Decode),
+            _ => Err(format!("Unknown transform mode {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mode::Encode => write!(f, "encode"),
+            Mode::Decode => write!(f, "decode"),
+        }
+    }
+}
+
+/// The transforms of one direction, run one after another
+pub struct Chain {
+    stages: Vec<Box<dyn Transform>>,
+    input: Vec<u8>,
+    output: Vec<u8>,
+}
+
+impl Chain {
+    /// Returns `None` without any transforms, so the forwarder can skip the
+    /// chain entirely
+    pub fn new(transforms: &Transforms, mode: Mode, from: Side) -> Option<Chain> {
+        if transforms.0.is_empty() {
+            return None;
+        }
+        let encode = (mode == Mode::Encode) == (from == Side::Client);
+        let stages = if encode {
+            transforms.0.iter().map(encoder).collect()
+        } else {
+            transforms.0.iter().rev().map(decoder).collect()
+        };
+        Some(Chain {
+            stages,
+            input: Vec::new(),
+            output: Vec::new(),
+        })
+    }
+
+    fn run(&mut self, chunk: &[u8], finish: bool, out: &mut Vec<u8>) -> io::Result<()> {
+        self.input.clear();
+        self.input.extend_from_slice(chunk);
+        for stage in &mut self.stages {
+            self.output.clear();
+            stage.apply(&self.input, &mut self.output)?;
+            if finish {
+                stage.finish(&mut self.output)?;
+            }
+            mem::swap(&mut self.input, &mut self.output);
+        }
+        out.extend_from_slice(&self.input);
+        Ok(())
+    }
+}
+
+impl Transform for Chain {
+    fn apply(&mut self, chunk: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        self.run(chunk, false, out)
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        self.run(&[], true, out)
+    }
+}
+
+fn encoder(spec: &Spec) -> Box<dyn Transform> {
+    match spec {
+        Spec::Gzip => Box::new(GzEncoder::new(Vec::new(), Compression::fast())),
+        Spec::Zstd => Box::new(
+            zstd::stream::write::Encoder::new(Vec::new(), ZSTD_LEVEL)
+                .expect("Failed to create a zstd encoder"),
+        ),
+        Spec::Xor(key) => Box::new(Xor::new(key)),
+        Spec::Checksum => Box::new(ChecksumTag),
+    }
+}
+
+fn decoder(spec: &Spec) -> Box<dyn Transform> {
+    match spec {
+        Spec::Gzip => Box::new(GzDecoder::new(Vec::new())),
+        Spec::Zstd => Box::new(zio::Writer::new(
+            Vec::new(),
+            raw::Decoder::new().expect("Failed to create a zstd decoder"),
+        )),
+        Spec::Xor(key) => Box::new(Xor::new(key)),
+        Spec::Checksum => Box::new(ChecksumVerify::default()),
+    }
+}
+
+/// Flushing after every chunk costs some ratio, but the other end can decode
+/// each chunk as soon as it arrives
+impl Transform for GzEncoder<Vec<u8>> {
+    fn apply(&mut self, chunk: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        self.write_all(chunk)?;
+        self.flush()?;
+        out.append(self.get_mut());
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        self.try_finish()?;
+        out.append(self.get_mut());
+        Ok(())
+    }
+}
+
+impl Transform for GzDecoder<Vec<u8>> {
+    fn apply(&mut self, chunk: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        self.write_all(chunk)?;
+        self.flush()?;
+        out.append(self.get_mut());
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        self.try_finish()?;
+        out.append(self.get_mut());
+        Ok(())
+    }
+}
+
+impl Transform for zstd::stream::write::Encoder<'static, Vec<u8>> {
+    fn apply(&mut self, chunk: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        self.write_all(chunk)?;
+        self.flush()?;
+        out.append(self.get_mut());
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        self.do_finish()?;
+        out.append(self.get_mut());
+        Ok(())
+    }
+}
+
+/// The writer under `zstd::stream::write::Decoder`, which does not expose the
+/// `finish` that notices a frame cut short
+impl Transform for zio::Writer<Vec<u8>, raw::Decoder<'static>> {
+    fn apply(&mut self, chunk: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        self.write_all(chunk)?;
+        self.flush()?;
+        out.append(self.writer_mut());
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        // Fails with `UnexpectedEof` when the stream ends inside a frame
+        zio::Writer::finish(self)?;
+        out.append(self.writer_mut());
+        Ok(())
+    }
+}
+
+/// Only hides the bytes from a casual look, it is not encryption
+struct Xor {
+    key: Vec<u8>,
+    /// Where in the key the next byte continues, across chunks
+    at: usize,
+}
+
+impl Xor {
+    fn new(key: &[u8]) -> Xor {
+        Xor {
+            key: key.to_vec(),
+            at: 0,
+        }
+    }
+}
+
+impl Transform for Xor {
+    fn apply(&mut self, chunk: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        out.extend(chunk.iter().map(|byte| {
+            let byte = byte ^ self.key[self.at];
+            self.at = (self.at + 1) % self.key.len();
+            byte
+        }));
+        Ok(())
+    }
+}
+
+/// Frames every chunk as a big-endian u32 length, the bytes and their CRC32
+struct ChecksumTag;
+
+impl Transform for ChecksumTag {
+    fn apply(&mut self, chunk: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        if chunk.is_empty() {
+            return Ok(());
+        }
+        out.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+        out.extend_from_slice(chunk);
+        out.extend_from_slice(&crc32fast::hash(chunk).to_be_bytes());
+        Ok(())
+    }
+}
+
+/// Unframes what `ChecksumTag` wrote, failing on the first mismatch
+#[derive(Default)]
+struct ChecksumVerify {
+    /// The start of a frame that has not fully arrived yet
+    pending: Vec<u8>,
+}
+
+impl Transform for ChecksumVerify {
+    fn apply(&mut self, chunk: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        self.pending.extend_from_slice(chunk);
+        let mut at = 0;
+        while self.pending.len() - at >= 4 {
+            let len = u32::from_be_bytes(self.pending[at..at + 4].try_into().unwrap()) as usize;
+            if len > MAX_FRAME {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Checksum frame of {} bytes is too large", len),
+                ));
+            }
+            if self.pending.len() - at < 4 + len + 4 {
+                break;
+            }
+            let data = &self.pending[at + 4..at + 4 + len];
+            let crc = &self.pending[at + 4 + len..at + 8 + len];
+            if crc32fast::hash(data).to_be_bytes() != crc {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Checksum mismatch",
+                ));
+            }
+            out.extend_from_slice(data);
+            at += 8 + len;
+        }
+        self.pending.drain(..at);
+        Ok(())
+    }
+
+    fn finish(&mut self, _out: &mut Vec<u8>) -> io::Result<()> {
+        if self.pending.is_empty() {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Truncated checksum frame",
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chains(transforms: &str) -> (Chain, Chain) {
+        let transforms: Transforms = transforms.parse().unwrap();
+        let encode = Chain::new(&transforms, Mode::Encode, Side::Client).unwrap();
+        let decode = Chain::new(&transforms, Mode::Decode, Side::Client).unwrap();
+        (encode, decode)
+    }
+
+    fn encode(chain: &mut Chain, chunks: &[&[u8]]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for chunk in chunks {
+            chain.apply(chunk, &mut out).unwrap();
+        }
+        chain.finish(&mut out).unwrap();
+        out
+    }
+
+    /// Decodes `encoded` in pieces that do not line up with what was encoded
+    fn decode(chain: &mut Chain, encoded: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        for piece in encoded.chunks(7) {
+            chain.apply(piece, &mut out)?;
+        }
+        chain.finish(&mut out)?;
+        Ok(out)
+    }
+
+    const CHUNKS: &[&[u8]] = &[b"GET /test2 HTTP/1.1\r\n", b"", &[0; 5000], b"\r\n\r\n"];
+
+    fn plain() -> Vec<u8> {
+        CHUNKS.concat()
+    }
+
+    #[test]
+    fn round_trips() {
+        for transforms in &[
+            "gzip",
+            "zstd",
+            "xor:key",
+            "checksum",
+            "zstd, xor:k,checksum",
+        ] {
+            let (mut encoder, mut decoder) = chains(transforms);
+            let encoded = encode(&mut encoder, CHUNKS);
+            assert_ne!(encoded, plain(), "{}", transforms);
+            assert_eq!(
+                decode(&mut decoder, &encoded).unwrap(),
+                plain(),
+                "{}",
+                transforms
+            );
+        }
+    }
+
+    #[test]
+    fn directions_swap_with_the_mode() {
+        let transforms: Transforms = "gzip".parse().unwrap();
+        let mut encoder = Chain::new(&transforms, Mode::Decode, Side::Upstream).unwrap();
+        let mut decoder = Chain::new(&transforms, Mode::Encode, Side::Upstream).unwrap();
+        let encoded = encode(&mut encoder, CHUNKS);
+        assert_eq!(decode(&mut decoder, &encoded).unwrap(), plain());
+        assert!(Chain::new(&Transforms::default(), Mode::Encode, Side::Client).is_none());
+    }
+
+    #[test]
+    fn detects_truncated_streams() {
+        let expected = [
+            // flate2 reports a cut-off gzip trailer as invalid input
+            ("gzip", io::ErrorKind::InvalidInput),
+            ("zstd", io::ErrorKind::UnexpectedEof),
+            ("checksum", io::ErrorKind::UnexpectedEof),
+        ];
+        for (transforms, kind) in &expected {
+            let (mut encoder, mut decoder) = chains(transforms);
+            let encoded = encode(&mut encoder, CHUNKS);
+            let err = decode(&mut decoder, &encoded[..encoded.len() - 3]).unwrap_err();
+            assert_eq!(err.kind(), *kind, "{}", transforms);
+        }
+    }
+
+    #[test]
+    fn detects_corrupt_checksum_frames() {
+        let (mut encoder, mut decoder) = chains("checksum");
+        let mut encoded = encode(&mut encoder, CHUNKS);
+        encoded[10] ^= 1;
+        let err = decode(&mut decoder, &encoded).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_oversized_checksum_frames() {
+        let (_, mut decoder) = chains("checksum");
+        let header = (MAX_FRAME as u32 + 1).to_be_bytes();
+        let err = decoder.apply(&header, &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // The largest frame still goes through
+        let (mut encoder, mut decoder) = chains("checksum");
+        let data = vec![1; MAX_FRAME];
+        let encoded = encode(&mut encoder, &[&data]);
+        assert_eq!(decode(&mut decoder, &encoded).unwrap(), data);
+    }
+
+    #[test]
+    fn parses_transforms() {
+        let transforms: Transforms = " gzip,xor:ab ,checksum,".parse().unwrap();
+        assert_eq!(
+            transforms.0,
+            vec![Spec::Gzip, Spec::Xor(b"ab".to_vec()), Spec::Checksum]
+        );
+        assert!("xor:".parse::<Transforms>().is_err());
+        assert!("gzip,rot13".parse::<Transforms>().is_err());
+    }
+}
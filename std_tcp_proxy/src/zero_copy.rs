@@ -0,0 +1,308 @@
+use crate::access_log::{CloseReason, Side};
+use std::{
+    io::{self, Read},
+    mem,
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    ptr,
+};
+use tracing::debug;
+
+/// How many buffers `MSG_ZEROCOPY` sends rotate through, so that reading the
+/// next chunk rarely waits for the kernel to finish with the previous one
+const BUFFERS: usize = 4;
+/// The `ee_origin` of `MSG_ZEROCOPY` notifications, which libc lacks
+const SO_EE_ORIGIN_ZEROCOPY: u8 = 5;
+/// Set in `ee_code` when the kernel copied the data after all, which it does
+/// on loopback and for devices that cannot send from user pages
+const SO_EE_CODE_ZEROCOPY_COPIED: u8 = 1;
+/// How long to wait for completions before checking the socket for errors
+const POLL_TIMEOUT_MS: libc::c_int = 1000;
+
+/// Moves the bytes read `from` one side to the other with `splice(2)`
+/// through a pipe, so they never enter userspace. Returns why it stopped and
+/// the byte count, like `forward`.
+pub fn splice<R: AsRawFd, W: AsRawFd>(
+    read: &R,
+    write: &W,
+    from: Side,
+    pipe_size: usize,
+) -> (CloseReason, u64) {
+    let (pipe_read, pipe_write, capacity) = match pipe(pipe_size) {
+        Ok(pipe) => pipe,
+        Err(err) => return (CloseReason::Error(err.kind()), 0),
+    };
+    let mut bytes = 0;
+    loop {
+        let n = match splice_once(read.as_raw_fd(), pipe_write.as_raw_fd(), capacity) {
+            Ok(0) => return (CloseReason::Eof(from), bytes),
+            Ok(n) => n,
+            Err(err) => return (CloseReason::ReadError(from, err.kind()), bytes),
+        };
+        let mut left = n;
+        while left > 0 {
+            match splice_once(pipe_read.as_raw_fd(), write.as_raw_fd(), left) {
+                Ok(0) => {
+                    let kind = io::ErrorKind::WriteZero;
+                    return (CloseReason::WriteError(from.other(), kind), bytes);
+                }
+                Ok(m) => left -= m,
+                Err(err) => return (CloseReason::WriteError(from.other(), err.kind()), bytes),
+            }
+        }
+        bytes += n as u64;
+    }
+}
+
+fn splice_once(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    loop {
+        let null = ptr::null_mut();
+        let n = unsafe { libc::splice(from, null, to, null, len, libc::SPLICE_F_MOVE) };
+        if n >= 0 {
+            return Ok(n as usize);
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// A pipe and how many bytes it holds: `size` when the kernel allows it,
+/// rounded up to whole pages
+fn pipe(size: usize) -> io::Result<(OwnedFd, OwnedFd, usize)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    let size = size.min(libc::c_int::MAX as usize) as libc::c_int;
+    // Unprivileged processes are capped at /proc/sys/fs/pipe-max-size
+    unsafe { libc::fcntl(write.as_raw_fd(), libc::F_SETPIPE_SZ, size) };
+    let capacity = unsafe { libc::fcntl(write.as_raw_fd(), libc::F_GETPIPE_SZ) };
+    if capacity < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((read, write, capacity as usize))
+}
+
+/// Turns on `SO_ZEROCOPY`, which `MSG_ZEROCOPY` sends need. Only TCP sockets
+/// support it.
+pub fn enable_zerocopy<W: AsRawFd>(write: &W) -> io::Result<()> {
+    let on: libc::c_int = 1;
+    let set = unsafe {
+        libc::setsockopt(
+            write.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_ZEROCOPY,
+            &on as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if set < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Copies the bytes read `from` one side to the other like `forward`, but
+/// sends them with `MSG_ZEROCOPY`, so the kernel transmits straight from the
+/// buffers. A buffer is only read into again once the kernel reports on the
+/// socket's error queue that it is done with it. `enable_zerocopy` has to
+/// succeed on `write` first.
+pub fn send_zerocopy<R: Read, W: AsRawFd>(
+    read: &mut R,
+    write: &W,
+    from: Side,
+    buf_size: usize,
+) -> (CloseReason, u64) {
+    let mut buffers = vec![vec![0u8; buf_size]; BUFFERS];
+    // The last send from each buffer, which has to complete before the
+    // buffer is reused
+    let mut last_sends: [Option<u32>; BUFFERS] = [None; BUFFERS];
+    let mut completions = Completions::new(write.as_raw_fd());
+    let mut bytes = 0;
+    let mut i = 0;
+    let result = 'copy: loop {
+        if let Some(send) = last_sends[i] {
+            if let Err(err) = completions.wait_for(send) {
+                break (CloseReason::WriteError(from.other(), err.kind()), bytes);
+            }
+        }
+        let n = match read.read(&mut buffers[i]) {
+            Ok(0) => break (CloseReason::Eof(from), bytes),
+            Ok(n) => n,
+            Err(err) => break (CloseReason::ReadError(from, err.kind()), bytes),
+        };
+        let mut sent = 0;
+        while sent < n {
+            match completions.send(&buffers[i][sent..n]) {
+                Ok(m) => {
+                    sent += m;
+                    last_sends[i] = Some(completions.sent.wrapping_sub(1));
+                }
+                Err(err) => break 'copy (CloseReason::WriteError(from.other(), err.kind()), bytes),
+            }
+        }
+        bytes += n as u64;
+        i = (i + 1) % BUFFERS;
+    };
+
+    // The kernel may still be reading from the buffers, and freeing them
+    // could put other data on the wire
+    if let Err(err) = completions.wait_for_all() {
+        debug!(error = %err, "gave up waiting for zerocopy completions");
+        mem::forget(buffers);
+    }
+    debug!(
+        sends = completions.sent,
+        copied = completions.copied,
+        "zerocopy sends completed"
+    );
+    result
+}
+
+/// The `MSG_ZEROCOPY` sends on one socket. The kernel numbers them from 0,
+/// and TCP reports them done in order, as ranges of those numbers.
+struct Completions {
+    fd: RawFd,
+    /// How many sends there have been, which is the next one's number
+    sent: u32,
+    /// Every send numbered below this one is done
+    done: u32,
+    /// Sends the kernel copied after all
+    copied: u32,
+}
+
+impl Completions {
+    fn new(fd: RawFd) -> Completions {
+        Completions {
+            fd,
+            sent: 0,
+            done: 0,
+            copied: 0,
+        }
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        loop {
+            let n = unsafe {
+                libc::send(
+                    self.fd,
+                    buf.as_ptr() as *const libc::c_void,
+                    buf.len(),
+                    libc::MSG_ZEROCOPY,
+                )
+            };
+            if n >= 0 {
+                self.sent = self.sent.wrapping_add(1);
+                return Ok(n as usize);
+            }
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                // Too many sends in flight for the socket's option memory
+                Some(libc::ENOBUFS) if self.done != self.sent => self.wait_for_all()?,
+                _ => return Err(err),
+            }
+        }
+    }
+
+    fn wait_for(&mut self, send: u32) -> io::Result<()> {
+        // Wrapping comparison, as the numbers wrap around after 2^32 sends
+        while (send.wrapping_sub(self.done) as i32) >= 0 {
+            self.reap()?;
+        }
+        Ok(())
+    }
+
+    fn wait_for_all(&mut self) -> io::Result<()> {
+        while self.done != self.sent {
+            self.reap()?;
+        }
+        Ok(())
+    }
+
+    /// Reads one notification from the error queue, waiting for it if there
+    /// is none yet
+    fn reap(&mut self) -> io::Result<()> {
+        let mut control = [0u64; 16];
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+        let flags = libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT;
+        let mut woken = false;
+        while unsafe { libc::recvmsg(self.fd, &mut msg, flags) } < 0 {
+            let err = io::Error::last_os_error();
+            match err.kind() {
+                io::ErrorKind::Interrupted => {}
+                // A pending socket error also wakes the poll, without a
+                // notification to read
+                io::ErrorKind::WouldBlock if woken => {
+                    self.take_error()?;
+                    woken = self.poll()?;
+                }
+                io::ErrorKind::WouldBlock => woken = self.poll()?,
+                _ => return Err(err),
+            }
+        }
+
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+        while !cmsg.is_null() {
+            let (level, kind) = unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type) };
+            let recverr = (level == libc::SOL_IP && kind == libc::IP_RECVERR)
+                || (level == libc::SOL_IPV6 && kind == libc::IPV6_RECVERR);
+            if recverr {
+                let data = unsafe { libc::CMSG_DATA(cmsg) } as *const libc::sock_extended_err;
+                let err = unsafe { ptr::read_unaligned(data) };
+                if err.ee_errno == 0 && err.ee_origin == SO_EE_ORIGIN_ZEROCOPY {
+                    // `ee_info` to `ee_data` are the sends that completed
+                    self.done = err.ee_data.wrapping_add(1);
+                    if err.ee_code & SO_EE_CODE_ZEROCOPY_COPIED != 0 {
+                        let sends = err.ee_data.wrapping_sub(err.ee_info).wrapping_add(1);
+                        self.copied = self.copied.wrapping_add(sends);
+                    }
+                }
+            }
+            cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+        }
+        Ok(())
+    }
+
+    /// Waits for the error queue, and returns whether it woke up for it
+    fn poll(&self) -> io::Result<bool> {
+        let mut fd = libc::pollfd {
+            fd: self.fd,
+            events: 0,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&mut fd, 1, POLL_TIMEOUT_MS) } < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+        Ok(fd.revents & libc::POLLERR != 0)
+    }
+
+    /// Returns the socket's pending error, if there is one
+    fn take_error(&self) -> io::Result<()> {
+        let mut error: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let got = unsafe {
+            libc::getsockopt(
+                self.fd,
+                libc::SOL_SOCKET,
+                libc::SO_ERROR,
+                &mut error as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if got < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if error != 0 {
+            return Err(io::Error::from_raw_os_error(error));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,172 @@
+use clap::Clap;
+use std::{
+    collections::BTreeMap,
+    convert::TryInto,
+    fs::File,
+    io::{self, BufReader, Read, Write},
+    net::{Shutdown, TcpStream},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+#[macro_use]
+extern crate lazy_static;
+
+/// Starts every capture file, followed by the format version
+const MAGIC: &[u8; 8] = b"PROXYTAP";
+const VERSION: u16 = 1;
+
+/// Replays a capture written by a proxy's `--tap` option: every captured
+/// connection is opened again and sent what its client sent
+#[derive(Clap, Debug)]
+struct Args {
+    /// The capture file to replay
+    #[clap(short, long)]
+    pub capture: PathBuf,
+    /// The address to send the client bytes to
+    #[clap(short, long, default_value = "127.0.0.1:20000")]
+    pub target: String,
+    /// Only replay the connection with this id
+    #[clap(long)]
+    pub connection: Option<u64>,
+    /// Keep the captured gaps between chunks and between connections, instead
+    /// of sending as fast as possible
+    #[clap(long)]
+    pub timing: bool,
+    /// How many connections to replay at once, each on a thread of its own
+    /// plus one that drains the responses. With `--timing`, connections that
+    /// overlapped more than this start late.
+    #[clap(long, default_value = "64")]
+    pub workers: usize,
+}
+
+lazy_static! {
+    static ref ARGS: Args = Args::parse();
+}
+
+/// Everything captured on one connection
+#[derive(Default)]
+struct Captured {
+    /// Microseconds since the start of the capture, and the bytes the client sent
+    chunks: Vec<(u64, Vec<u8>)>,
+    /// What the upstream sent back at the time
+    bytes_down: u64,
+}
+
+fn main() {
+    let connections = read_capture().expect("Failed to read the capture");
+    println!(
+        "tap replay:: capture={}, target={}, connections={}, timing={}, workers={}",
+        ARGS.capture.display(),
+        &ARGS.target,
+        connections.len(),
+        &ARGS.timing,
+        ARGS.workers,
+    );
+
+    // Connections go out in the order they were captured, to whichever
+    // worker is free
+    let start = Instant::now();
+    let queue = Arc::new(Mutex::new(connections.into_iter()));
+    let workers: Vec<_> = (0..ARGS.workers.max(1))
+        .map(|_| {
+            let queue = Arc::clone(&queue);
+            std::thread::spawn(move || loop {
+                let next = queue.lock().unwrap().next();
+                match next {
+                    Some((id, captured)) => replay(id, captured, start),
+                    None => return,
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        let _ = worker.join();
+    }
+}
+
+fn read_capture() -> io::Result<BTreeMap<u64, Captured>> {
+    let mut file = BufReader::new(File::open(&ARGS.capture)?);
+    let mut header = [0; 10];
+    file.read_exact(&mut header)?;
+    if &header[..8] != MAGIC || header[8..] != VERSION.to_be_bytes() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Not a version 1 proxy tap capture",
+        ));
+    }
+
+    let mut connections: BTreeMap<u64, Captured> = BTreeMap::new();
+    let mut first_micros = None;
+    let mut record = [0; 21];
+    loop {
+        // A record cut short by a proxy that was still writing ends the capture
+        if file.read_exact(&mut record).is_err() {
+            break;
+        }
+        let micros = u64::from_be_bytes(record[0..8].try_into().unwrap());
+        let id = u64::from_be_bytes(record[8..16].try_into().unwrap());
+        let direction = record[16];
+        let len = u32::from_be_bytes(record[17..21].try_into().unwrap()) as usize;
+        let mut data = vec![0; len];
+        if file.read_exact(&mut data).is_err() {
+            break;
+        }
+
+        let offset = micros.saturating_sub(*first_micros.get_or_insert(micros));
+        if ARGS.connection.is_some() && ARGS.connection != Some(id) {
+            continue;
+        }
+        let captured = connections.entry(id).or_default();
+        if direction == 0 {
+            captured.chunks.push((offset, data));
+        } else {
+            captured.bytes_down += len as u64;
+        }
+    }
+    Ok(connections)
+}
+
+fn replay(id: u64, captured: Captured, start: Instant) {
+    let mut stream = match TcpStream::connect(&ARGS.target) {
+        Ok(stream) => stream,
+        Err(err) => {
+            println!("connection {}: failed to connect: {}", id, err);
+            return;
+        }
+    };
+
+    // Drain the responses so the target never blocks on a full buffer
+    let mut responses = stream.try_clone().expect("Failed to clone the stream");
+    let drain = std::thread::spawn(move || {
+        let mut sink = io::sink();
+        io::copy(&mut responses, &mut sink).unwrap_or(0)
+    });
+
+    let mut bytes_up = 0;
+    for (offset, data) in &captured.chunks {
+        if ARGS.timing {
+            let at = Duration::from_micros(*offset);
+            if let Some(wait) = at.checked_sub(start.elapsed()) {
+                std::thread::sleep(wait);
+            }
+        }
+        if let Err(err) = stream.write_all(data) {
+            println!("connection {}: failed to send: {}", id, err);
+            break;
+        }
+        bytes_up += data.len();
+    }
+    let _ = stream.shutdown(Shutdown::Write);
+
+    let bytes_down = drain.join().unwrap_or(0);
+    println!(
+        "connection {}: sent {} bytes in {} chunks, received {} bytes ({} captured)",
+        id,
+        bytes_up,
+        captured.chunks.len(),
+        bytes_down,
+        captured.bytes_down,
+    );
+}
@@ -0,0 +1,49 @@
+#! /usr/bin/env bash
+
+# Checks the Tokio proxy's transparent mode (`--destination original`) in a
+# throwaway network namespace. An nftables REDIRECT rule sends connections for
+# the testserver at 127.0.0.1:20002 from 127.0.0.3 to the proxy, which has to
+# connect on to the testserver. A client that reaches the proxy directly has
+# to be closed with `loop`. Needs nft and unprivileged user namespaces. TPROXY
+# rules (`--transparent`) are not covered.
+
+set -e
+
+if ! command -v nft > /dev/null
+then
+    echo "nft is required" >&2
+    exit 1
+fi
+
+cargo build --release --manifest-path ./testserver/Cargo.toml
+
+cargo build --release --manifest-path ./tokio_tcp_proxy/Cargo.toml
+
+ACCESS_LOG=$(mktemp)
+trap 'rm -f "$ACCESS_LOG"' EXIT
+
+unshare --net --map-root-user bash -eu -c '
+    ip link set lo up
+    nft add table ip nat
+    nft "add chain ip nat output { type nat hook output priority -100; }"
+    nft add rule ip nat output ip saddr 127.0.0.3 tcp dport 20002 redirect to :20000
+
+    ./testserver/target/release/testserver &
+    ./tokio_tcp_proxy/target/release/tokio_tcp_proxy --destination original \
+        --access-log "$1" --log-level warn &
+    trap "kill \$(jobs -p)" EXIT
+    sleep 1
+
+    curl --silent --fail --output /dev/null --interface 127.0.0.3 http://127.0.0.1:20002/test2
+    if curl --silent --output /dev/null http://127.0.0.1:20000/test2
+    then
+        echo "A direct connection to the proxy was not refused" >&2
+        exit 1
+    fi
+    sleep 0.5
+' _ "$ACCESS_LOG"
+
+cat "$ACCESS_LOG"
+grep -q "upstream=127.0.0.1:20002 " "$ACCESS_LOG"
+grep -q "close=loop" "$ACCESS_LOG"
+echo "transparent mode ok"
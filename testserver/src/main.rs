@@ -1,11 +1,16 @@
 use clap::Clap;
-use hyper::server::Server;
+use hyper::server::{accept, conn::Http, Server};
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response};
+use socket2::{Domain, Socket, Type};
 use std::{
     convert::Infallible,
+    io,
     mem::{self, MaybeUninit},
+    net::{SocketAddr, TcpListener},
 };
+use tokio::net::{UdpSocket, UnixListener};
+use tokio_native_tls::TlsAcceptor;
 
 #[macro_use]
 extern crate lazy_static;
@@ -13,9 +18,24 @@ extern crate lazy_static;
 /// A simple TCP proxy
 #[derive(Clap, Debug)]
 struct Args {
-    /// The address to listen on
+    /// The address to listen on, `host:port` or `unix:/path`
     #[clap(short, long, default_value = "127.0.0.1:20002")]
     pub listen: String,
+    /// Only accept HTTP/2 over cleartext with prior knowledge (h2c)
+    #[clap(long)]
+    pub http2_only: bool,
+    /// An optional address to echo UDP datagrams back from
+    #[clap(long)]
+    pub udp_listen: Option<String>,
+    /// Set IPV6_V6ONLY on an IPv6 listen address; `false` makes `[::]` dual-stack
+    #[clap(long)]
+    pub ipv6_only: Option<bool>,
+    /// A PEM certificate to serve HTTPS with, instead of plain HTTP
+    #[clap(long)]
+    pub tls_cert: Option<String>,
+    /// The PEM private key of `--tls-cert`, in PKCS #8
+    #[clap(long)]
+    pub tls_key: Option<String>,
 }
 
 const FIRST_SIZE: usize = 64 * 1024;
@@ -28,7 +48,17 @@ struct Data {
 
 impl std::fmt::Display for Args {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "listen={}", self.listen)
+        write!(f, "listen={}, http2_only={}", self.listen, self.http2_only)?;
+        if let Some(ipv6_only) = self.ipv6_only {
+            write!(f, ", ipv6_only={}", ipv6_only)?;
+        }
+        if let Some(udp_listen) = &self.udp_listen {
+            write!(f, ", udp_listen={}", udp_listen)?;
+        }
+        if let Some(tls_cert) = &self.tls_cert {
+            write!(f, ", tls_cert={}", tls_cert)?;
+        }
+        Ok(())
     }
 }
 
@@ -42,8 +72,10 @@ impl Data {
             for i in 0..FIRST_BIN_SIZE {
                 stuff[i] = MaybeUninit::new((i % 256) as u8);
             }
-            let stuff = mem::transmute::<_, [u8; FIRST_BIN_SIZE]>(stuff);
-            let mut first = mem::transmute::<_, [u8; FIRST_SIZE]>(first);
+            let stuff =
+                mem::transmute::<[MaybeUninit<u8>; FIRST_BIN_SIZE], [u8; FIRST_BIN_SIZE]>(stuff);
+            let mut first =
+                mem::transmute::<[MaybeUninit<u8>; FIRST_SIZE], [u8; FIRST_SIZE]>(first);
             hex::encode_to_slice(stuff, &mut first).expect("Could not encode data to hex");
             first
         };
@@ -70,6 +102,9 @@ async fn handle(req: Request<Body>) -> Result<Response<Body>, Infallible> {
             let r: &'static [u8] = &DATA.second;
             Ok(Response::new(r.into()))
         }
+        // Lets clients check what reached the server, e.g. through a proxy
+        // that corrupts bytes
+        "/echo" => Ok(Response::new(req.into_body())),
         _ => Ok(Response::builder()
             .status(404)
             .body("Not found".into())
@@ -77,18 +112,112 @@ async fn handle(req: Request<Body>) -> Result<Response<Body>, Infallible> {
     }
 }
 
+fn bind_tcp(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if let (true, Some(only_v6)) = (addr.is_ipv6(), ARGS.ipv6_only) {
+        socket.set_only_v6(only_v6)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+/// Sends every datagram back to where it came from
+async fn udp_echo(addr: &str) {
+    let socket = UdpSocket::bind(addr)
+        .await
+        .expect("Failed to bind to UDP listen address");
+    let mut buf = vec![0u8; 65536];
+    loop {
+        if let Ok((n, peer)) = socket.recv_from(&mut buf).await {
+            let _ = socket.send_to(&buf[..n], peer).await;
+        }
+    }
+}
+
+/// Reads `--tls-cert` and `--tls-key`, if given
+fn tls_acceptor() -> Option<TlsAcceptor> {
+    let cert = ARGS.tls_cert.as_ref()?;
+    let key = ARGS
+        .tls_key
+        .as_ref()
+        .expect("--tls-cert needs --tls-key as well");
+    let cert = std::fs::read(cert).expect("Failed to read the TLS certificate");
+    let key = std::fs::read(key).expect("Failed to read the TLS key");
+    let identity =
+        native_tls::Identity::from_pkcs8(&cert, &key).expect("Invalid TLS certificate or key");
+    let acceptor = native_tls::TlsAcceptor::new(identity).expect("Failed to set up TLS");
+    Some(acceptor.into())
+}
+
+/// Serves HTTPS, with the TLS handshake of each connection on its own task
+async fn serve_tls(listener: TcpListener, acceptor: TlsAcceptor) {
+    listener
+        .set_nonblocking(true)
+        .expect("Failed to make the listener non-blocking");
+    let listener =
+        tokio::net::TcpListener::from_std(listener).expect("Failed to register the listener");
+    loop {
+        let socket = match listener.accept().await {
+            Ok((socket, _)) => socket,
+            Err(_) => continue,
+        };
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move {
+            if let Ok(stream) = acceptor.accept(socket).await {
+                let _ = Http::new()
+                    .http2_only(ARGS.http2_only)
+                    .serve_connection(stream, service_fn(handle))
+                    .await;
+            }
+        });
+    }
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
-    let addr = ARGS
-        .listen
-        .parse()
-        .expect("Could not parse listen address to SocketAddr");
+    if let Some(udp_listen) = &ARGS.udp_listen {
+        tokio::spawn(udp_echo(udp_listen));
+    }
 
-    let service = make_service_fn(|_| async { Ok::<_, hyper::Error>(service_fn(handle)) });
+    // Without `http2_only`, hyper still detects the h2c preface and serves
+    // HTTP/2 to clients with prior knowledge, next to plain HTTP/1.
+    if let Some(path) = ARGS.listen.strip_prefix("unix:") {
+        // Clean up a socket file left behind by a previous run
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path).expect("Failed to bind to unix socket");
+        let incoming =
+            accept::poll_fn(move |cx| listener.poll_accept(cx).map(|r| Some(r.map(|(s, _)| s))));
+        let service = make_service_fn(|_| async { Ok::<_, hyper::Error>(service_fn(handle)) });
+        let server = Server::builder(incoming)
+            .http2_only(ARGS.http2_only)
+            .serve(service);
 
-    let server = Server::bind(&addr).serve(service);
+        println!("Testserver listening on unix:{} ({})", path, *ARGS);
 
-    println!("Testserver listening on http://{}", addr);
+        server.await.unwrap();
+    } else {
+        let addr = ARGS
+            .listen
+            .parse()
+            .expect("Could not parse listen address to SocketAddr");
+        let listener = bind_tcp(addr).expect("Failed to bind to listen address");
 
-    server.await.unwrap();
+        if let Some(acceptor) = tls_acceptor() {
+            println!("Testserver listening on https://{} ({})", addr, *ARGS);
+            serve_tls(listener, acceptor).await;
+            return;
+        }
+
+        let service = make_service_fn(|_| async { Ok::<_, hyper::Error>(service_fn(handle)) });
+        let server = Server::from_tcp(listener)
+            .expect("Failed to register the listener")
+            .http2_only(ARGS.http2_only)
+            .serve(service);
+
+        println!("Testserver listening on http://{} ({})", addr, *ARGS);
+
+        server.await.unwrap();
+    }
 }
@@ -0,0 +1,72 @@
+use crate::REGISTRY;
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use serde::Serialize;
+use std::{convert::Infallible, net::SocketAddr};
+use tracing::{error, info};
+
+#[derive(Serialize)]
+struct Status {
+    draining: bool,
+}
+
+/// Serves the admin API:
+///
+/// - `GET /connections` lists the open connections
+/// - `DELETE /connections/<id>` closes a connection
+/// - `GET /stats` counts open connections and rejected clients, and shows
+///   the state of each upstream's circuit breaker
+/// - `GET /drain` shows whether the proxy is draining
+/// - `POST /drain` starts draining, `DELETE /drain` stops it
+pub async fn serve(addr: SocketAddr) {
+    let service = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle)) });
+    let server = Server::bind(&addr).serve(service);
+
+    info!(%addr, "admin API listening");
+
+    if let Err(err) = server.await {
+        error!(error = %err, "admin API failed");
+    }
+}
+
+async fn handle(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let path: Vec<&str> = req.uri().path().trim_matches('/').split('/').collect();
+    let response = match (req.method(), path.as_slice()) {
+        (&Method::GET, ["connections"]) => json(&REGISTRY.list()),
+        (&Method::DELETE, ["connections", id]) => match id.parse() {
+            Ok(id) if REGISTRY.kill(id) => status(StatusCode::NO_CONTENT),
+            _ => status(StatusCode::NOT_FOUND),
+        },
+        (&Method::GET, ["stats"]) => json(&REGISTRY.stats()),
+        (&Method::GET, ["drain"]) => json(&Status {
+            draining: REGISTRY.is_draining(),
+        }),
+        (&Method::POST, ["drain"]) | (&Method::DELETE, ["drain"]) => {
+            REGISTRY.set_draining(req.method() == Method::POST);
+            json(&Status {
+                draining: REGISTRY.is_draining(),
+            })
+        }
+        _ => status(StatusCode::NOT_FOUND),
+    };
+    Ok(response)
+}
+
+fn json<T: Serialize>(value: &T) -> Response<Body> {
+    match serde_json::to_vec(value) {
+        Ok(body) => Response::builder()
+            .header("content-type", "application/json")
+            .body(body.into())
+            .unwrap(),
+        Err(_) => status(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
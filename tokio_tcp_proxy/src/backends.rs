@@ -0,0 +1,494 @@
+use crate::{
+    breaker::{Breaker, State as BreakerState},
+    net::{Address, Connector, Stream},
+    pool::Pool,
+};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    net::IpAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// What keeps a client on the same backend
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Affinity {
+    /// Every connection goes to the next backend in turn
+    None,
+    /// Connections from the same client address go to the same backend
+    ClientIp,
+    /// Connections that start with the same bytes, such as a session token,
+    /// go to the same backend
+    Prefix,
+}
+
+impl FromStr for Affinity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Affinity, String> {
+        match s {
+            "none" => Ok(Affinity::None),
+            "client-ip" => Ok(Affinity::ClientIp),
+            "prefix" => Ok(Affinity::Prefix),
+            _ => Err(format!("Unknown affinity {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Affinity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Affinity::None => write!(f, "none"),
+            Affinity::ClientIp => write!(f, "client-ip"),
+            Affinity::Prefix => write!(f, "prefix"),
+        }
+    }
+}
+
+/// The key a client IP address is remembered by. IPv4 clients of a
+/// dual-stack listener get the same key as on an IPv4 one.
+pub fn client_ip_key(ip: IpAddr) -> Vec<u8> {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+/// The upstream backends of a route. New clients are spread over them in
+/// proportion to their weights, and with a sticky table, clients it
+/// remembers go back to their backend.
+pub struct Backends {
+    backends: Vec<Backend>,
+    /// How long a new or recovered backend takes to reach its full weight
+    slow_start: Option<Duration>,
+    sticky: Option<StickyTable>,
+}
+
+pub struct Backend {
+    address: Address,
+    connector: Arc<Connector>,
+    weight: u32,
+    breaker: Option<Arc<Breaker>>,
+    warm: Option<Arc<Pool>>,
+    state: Mutex<State>,
+}
+
+#[derive(Clone, Copy)]
+struct State {
+    /// The smooth weighted round-robin counter, which grows by the effective
+    /// weight on every pick and shrinks by the total when it wins
+    current: f64,
+    /// When the backend started taking traffic, while it is ramping up
+    warming: Option<Instant>,
+    /// Whether the last connect to it failed
+    failing: bool,
+}
+
+impl Backend {
+    pub fn new(
+        address: Address,
+        connector: Arc<Connector>,
+        weight: u32,
+        breaker: Option<Arc<Breaker>>,
+        warm: Option<Arc<Pool>>,
+    ) -> Backend {
+        Backend {
+            address,
+            connector,
+            weight,
+            breaker,
+            warm,
+            state: Mutex::new(State {
+                current: 0.0,
+                warming: None,
+                failing: false,
+            }),
+        }
+    }
+}
+
+impl Backends {
+    /// Backends that `previous` already had keep their state, their clients
+    /// in the sticky table, and their breaker and warm connections while
+    /// their settings stay the same.
+    /// Others only ramp up when they join a running route, as all of them are
+    /// cold at startup alike.
+    pub fn new(
+        mut backends: Vec<Backend>,
+        slow_start: Option<Duration>,
+        sticky: Option<StickyTable>,
+        previous: Option<&Backends>,
+    ) -> Backends {
+        if let Some(previous) = previous {
+            let now = Instant::now();
+            for backend in &mut backends {
+                let known = previous
+                    .backends
+                    .iter()
+                    .find(|old| old.address == backend.address);
+                let old = match known {
+                    Some(old) => old,
+                    None => {
+                        backend.state.get_mut().unwrap().warming = Some(now);
+                        continue;
+                    }
+                };
+                *backend.state.get_mut().unwrap() = *old.state.lock().unwrap();
+                if let (Some(new), Some(old)) = (&backend.breaker, &old.breaker) {
+                    if new.thresholds() == old.thresholds() {
+                        backend.breaker = Some(old.clone());
+                    }
+                }
+                if let (Some(new), Some(old)) = (&backend.warm, &old.warm) {
+                    if new.same_settings(old) {
+                        backend.warm = Some(old.clone());
+                    }
+                }
+            }
+        }
+        // Clients stay with their backend across the reload, while it is
+        // still there
+        if let (Some(sticky), Some(previous)) = (&sticky, previous) {
+            if let Some(old_sticky) = &previous.sticky {
+                sticky.carry_over(old_sticky, |old| {
+                    let address = &previous.backends[old].address;
+                    backends
+                        .iter()
+                        .position(|backend| &backend.address == address)
+                });
+            }
+        }
+        for pool in backends.iter().filter_map(|backend| backend.warm.as_ref()) {
+            pool.start();
+        }
+        Backends {
+            backends,
+            slow_start,
+            sticky,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.backends.len()
+    }
+
+    /// The backend for a client with this affinity key, remembering it for
+    /// the next time. There is none while the breakers of all backends are
+    /// open.
+    pub fn pick(&self, key: Option<&[u8]>) -> Option<usize> {
+        if self.backends.len() == 1 {
+            return Some(0).filter(|&backend| self.allow(backend));
+        }
+        let sticky = self.sticky.as_ref().zip(key);
+        let remembered = sticky
+            .and_then(|(sticky, key)| sticky.get(key))
+            .filter(|&backend| self.backends[backend].weight > 0 && self.available(backend));
+        if let Some(backend) = remembered {
+            return Some(backend).filter(|&backend| self.allow(backend));
+        }
+        let backend = match self.next() {
+            Some(backend) => backend,
+            None => {
+                // Every breaker that is open turned this client away
+                for backend in &self.backends {
+                    if let Some(breaker) = &backend.breaker {
+                        breaker.fail_fast();
+                    }
+                }
+                return None;
+            }
+        };
+        if !self.allow(backend) {
+            return None;
+        }
+        if let Some((sticky, key)) = sticky {
+            sticky.insert(key, backend);
+        }
+        Some(backend)
+    }
+
+    /// Smooth weighted round-robin: every backend's counter grows by its
+    /// weight, and the highest one wins and gives back the total. Picks
+    /// follow the weights exactly, interleaved rather than in runs. Backends
+    /// with an open breaker sit out.
+    fn next(&self) -> Option<usize> {
+        let now = Instant::now();
+        let mut states: Vec<_> = self
+            .backends
+            .iter()
+            .map(|backend| backend.state.lock().unwrap())
+            .collect();
+        let mut total = 0.0;
+        let mut best: Option<usize> = None;
+        for (i, backend) in self.backends.iter().enumerate() {
+            if !self.available(i) {
+                continue;
+            }
+            let weight = backend.weight as f64 * self.ramp(&mut states[i], now);
+            states[i].current += weight;
+            total += weight;
+            if best.is_none_or(|best| states[i].current > states[best].current) {
+                best = Some(i);
+            }
+        }
+        if total == 0.0 {
+            return None;
+        }
+        let best = best?;
+        states[best].current -= total;
+        Some(best)
+    }
+
+    fn available(&self, backend: usize) -> bool {
+        self.backends[backend]
+            .breaker
+            .as_ref()
+            .is_none_or(|breaker| breaker.available())
+    }
+
+    fn allow(&self, backend: usize) -> bool {
+        self.backends[backend]
+            .breaker
+            .as_ref()
+            .is_none_or(|breaker| breaker.allow())
+    }
+
+    /// The share of its weight a backend gets, which grows linearly from
+    /// almost nothing over the slow-start window
+    fn ramp(&self, state: &mut State, now: Instant) -> f64 {
+        let (window, since) = match (self.slow_start, state.warming) {
+            (Some(window), Some(since)) => (window, since),
+            _ => return 1.0,
+        };
+        let ramp = now.duration_since(since).as_secs_f64() / window.as_secs_f64();
+        if ramp >= 1.0 {
+            state.warming = None;
+            return 1.0;
+        }
+        ramp.max(0.01)
+    }
+
+    /// Records a successful connect. A backend that was failing ramps up
+    /// again from here.
+    pub fn connected(&self, backend: usize) {
+        if let Some(breaker) = &self.backends[backend].breaker {
+            breaker.record(true);
+        }
+        let mut state = self.backends[backend].state.lock().unwrap();
+        if state.failing {
+            state.failing = false;
+            state.warming = Some(Instant::now());
+        }
+    }
+
+    /// Records a failed connect, and lets the client move to another backend
+    /// next time
+    pub fn failed(&self, backend: usize, key: Option<&[u8]>) {
+        if let Some(breaker) = &self.backends[backend].breaker {
+            breaker.record(false);
+        }
+        self.backends[backend].state.lock().unwrap().failing = true;
+        if let Some((sticky, key)) = self.sticky.as_ref().zip(key) {
+            sticky.remove(key);
+        }
+    }
+
+    pub fn address(&self, backend: usize) -> &Address {
+        &self.backends[backend].address
+    }
+
+    pub fn connector(&self, backend: usize) -> &Connector {
+        &self.backends[backend].connector
+    }
+
+    /// A connection opened ahead of time, when the backend has one ready.
+    /// A half-open breaker's probe opens a new connection instead, as one
+    /// that was opened before says little about whether the upstream is back.
+    pub fn take_warm(&self, backend: usize) -> Option<Stream> {
+        let backend = &self.backends[backend];
+        let probing = backend
+            .breaker
+            .as_ref()
+            .is_some_and(|breaker| breaker.state() == BreakerState::HalfOpen);
+        if probing {
+            return None;
+        }
+        backend.warm.as_ref()?.take()
+    }
+}
+
+/// Which backend each recent client went to. Entries expire `ttl` after
+/// their last use, and once the table holds `capacity` of them, a new client
+/// takes the place of the one unused for longest. Entries are also kept in
+/// the order of their last use, so neither takes a scan of the table.
+pub struct StickyTable {
+    /// What the keys are, which a reload may change
+    affinity: Affinity,
+    inner: Mutex<StickyInner>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+#[derive(Default)]
+struct StickyInner {
+    entries: HashMap<Vec<u8>, Sticky>,
+    /// The keys by the `tick` of their last use, least recent first
+    order: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+}
+
+struct Sticky {
+    backend: usize,
+    used: Instant,
+    tick: u64,
+}
+
+impl StickyTable {
+    pub fn new(affinity: Affinity, capacity: usize, ttl: Duration) -> StickyTable {
+        StickyTable {
+            affinity,
+            inner: Mutex::new(StickyInner::default()),
+            capacity: capacity.max(1),
+            ttl,
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Option<usize> {
+        let mut inner = self.inner.lock().unwrap();
+        let entry = inner.remove(key)?;
+        if entry.used.elapsed() >= self.ttl {
+            return None;
+        }
+        inner.push(key.to_vec(), entry.backend, Instant::now());
+        Some(entry.backend)
+    }
+
+    fn insert(&self, key: &[u8], backend: usize) {
+        self.insert_used(key.to_vec(), backend, Instant::now());
+    }
+
+    fn insert_used(&self, key: Vec<u8>, backend: usize, used: Instant) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.remove(&key);
+        inner.expire(self.ttl);
+        if inner.entries.len() >= self.capacity {
+            let oldest = inner.order.values().next().cloned();
+            if let Some(oldest) = oldest {
+                inner.remove(&oldest);
+            }
+        }
+        inner.push(key, backend, used);
+    }
+
+    fn remove(&self, key: &[u8]) {
+        self.inner.lock().unwrap().remove(key);
+    }
+
+    /// Takes over the clients `previous` remembers, for the backends that are
+    /// still there. `backend` maps an old backend index to the new one.
+    fn carry_over(&self, previous: &StickyTable, backend: impl Fn(usize) -> Option<usize>) {
+        if previous.affinity != self.affinity {
+            return;
+        }
+        let previous = previous.inner.lock().unwrap();
+        // Least recent first, so a smaller table keeps the most recent
+        for key in previous.order.values() {
+            let entry = &previous.entries[key];
+            if entry.used.elapsed() >= self.ttl {
+                continue;
+            }
+            if let Some(backend) = backend(entry.backend) {
+                self.insert_used(key.clone(), backend, entry.used);
+            }
+        }
+    }
+}
+
+impl StickyInner {
+    /// Adds an entry as the most recently used one
+    fn push(&mut self, key: Vec<u8>, backend: usize, used: Instant) {
+        self.tick += 1;
+        let tick = self.tick;
+        self.order.insert(tick, key.clone());
+        self.entries.insert(
+            key,
+            Sticky {
+                backend,
+                used,
+                tick,
+            },
+        );
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Sticky> {
+        let entry = self.entries.remove(key)?;
+        self.order.remove(&entry.tick);
+        Some(entry)
+    }
+
+    /// Drops the entries unused for `ttl`, which are the least recent ones
+    fn expire(&mut self, ttl: Duration) {
+        while let Some(key) = self.order.values().next() {
+            if self.entries[key].used.elapsed() < ttl {
+                return;
+            }
+            let key = key.clone();
+            self.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::breaker::Thresholds;
+
+    fn backend(name: &str, breaker: &Arc<Breaker>) -> Backend {
+        let path = std::path::PathBuf::from(name);
+        Backend::new(
+            Address::Unix(path.clone()),
+            Arc::new(Connector::Unix(path)),
+            1,
+            Some(breaker.clone()),
+            None,
+        )
+    }
+
+    #[test]
+    fn counts_clients_failed_fast_while_all_breakers_are_open() {
+        let thresholds = Thresholds {
+            failures: 1,
+            failure_rate: 0.0,
+            window: 0,
+            open_for: Duration::from_secs(3600),
+        };
+        let breakers: Vec<_> = ["a", "b"]
+            .iter()
+            .map(|name| Arc::new(Breaker::new(name.to_string(), thresholds)))
+            .collect();
+        let backends = Backends::new(
+            breakers
+                .iter()
+                .zip(["a", "b"])
+                .map(|(breaker, name)| backend(name, breaker))
+                .collect(),
+            None,
+            None,
+            None,
+        );
+
+        breakers[0].record(false);
+        assert_eq!(backends.pick(None), Some(1));
+        assert_eq!(breakers[0].failed_fast(), 0);
+
+        breakers[1].record(false);
+        assert_eq!(backends.pick(None), None);
+        assert_eq!(backends.pick(None), None);
+        assert_eq!(breakers[0].failed_fast(), 2);
+        assert_eq!(breakers[1].failed_fast(), 2);
+    }
+}
@@ -0,0 +1,181 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+/// When a circuit breaker opens, and for how long
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Thresholds {
+    /// Consecutive failed connects that open the breaker; 0 never does
+    pub failures: u32,
+    /// The share of the last `window` connects that have to fail to open the
+    /// breaker; 0 never does
+    pub failure_rate: f64,
+    pub window: usize,
+    /// How long an open breaker fails connects fast before it lets one
+    /// through to probe the upstream
+    pub open_for: Duration,
+}
+
+impl Thresholds {
+    pub fn enabled(&self) -> bool {
+        self.failures > 0 || self.failure_rate > 0.0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    /// Connects go ahead
+    Closed,
+    /// Connects fail fast
+    Open,
+    /// One connect probes whether the upstream is back
+    HalfOpen,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            State::Closed => write!(f, "closed"),
+            State::Open => write!(f, "open"),
+            State::HalfOpen => write!(f, "half-open"),
+        }
+    }
+}
+
+/// Stops connecting to an upstream that keeps failing, so clients are turned
+/// away at once rather than each waiting for its own failed connect
+pub struct Breaker {
+    upstream: String,
+    thresholds: Thresholds,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    state: State,
+    /// When the breaker opened, or when the last probe went out
+    since: Instant,
+    consecutive: u32,
+    /// Whether each of the last `window` connects failed
+    recent: VecDeque<bool>,
+    failed_fast: u64,
+}
+
+impl Breaker {
+    pub fn new(upstream: String, thresholds: Thresholds) -> Breaker {
+        Breaker {
+            upstream,
+            thresholds,
+            inner: Mutex::new(Inner {
+                state: State::Closed,
+                since: Instant::now(),
+                consecutive: 0,
+                recent: VecDeque::with_capacity(thresholds.window),
+                failed_fast: 0,
+            }),
+        }
+    }
+
+    pub fn upstream(&self) -> &str {
+        &self.upstream
+    }
+
+    pub fn thresholds(&self) -> Thresholds {
+        self.thresholds
+    }
+
+    /// Whether a connect may go ahead. Once an open breaker has waited
+    /// `open_for`, it lets one connect through as a probe, and another each
+    /// `open_for` while the probe has not reported back.
+    pub fn allow(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        if inner.state == State::Closed {
+            return true;
+        }
+        if now.duration_since(inner.since) < self.thresholds.open_for {
+            inner.failed_fast += 1;
+            return false;
+        }
+        if inner.state == State::Open {
+            info!(upstream = %self.upstream, "circuit breaker half-open, probing");
+        }
+        inner.state = State::HalfOpen;
+        inner.since = now;
+        true
+    }
+
+    /// Whether `allow` would let a connect through right now
+    pub fn available(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.state == State::Closed || inner.since.elapsed() >= self.thresholds.open_for
+    }
+
+    /// Counts a client turned away because no upstream was available, when
+    /// this breaker is one of the reasons
+    pub fn fail_fast(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.state != State::Closed {
+            inner.failed_fast += 1;
+        }
+    }
+
+    /// Records how a connect that `allow` let through went
+    pub fn record(&self, success: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            State::HalfOpen if success => {
+                info!(upstream = %self.upstream, "circuit breaker closed");
+                inner.state = State::Closed;
+            }
+            State::HalfOpen => self.open(&mut inner, "probe failed"),
+            // A connect that started before the breaker opened
+            State::Open => {}
+            State::Closed => {
+                // Without a window there is no failure rate to keep track of
+                if self.thresholds.window > 0 {
+                    if inner.recent.len() == self.thresholds.window {
+                        inner.recent.pop_front();
+                    }
+                    inner.recent.push_back(!success);
+                }
+                inner.consecutive = if success { 0 } else { inner.consecutive + 1 };
+
+                let failures = self.thresholds.failures;
+                if failures > 0 && inner.consecutive >= failures {
+                    self.open(&mut inner, "consecutive failures");
+                    return;
+                }
+                let rate = self.thresholds.failure_rate;
+                let window = inner.recent.len();
+                let failed = inner.recent.iter().filter(|&&failed| failed).count();
+                if rate > 0.0
+                    && window == self.thresholds.window
+                    && failed as f64 >= rate * window as f64
+                {
+                    self.open(&mut inner, "failure rate");
+                }
+            }
+        }
+    }
+
+    fn open(&self, inner: &mut Inner, why: &str) {
+        warn!(upstream = %self.upstream, why, "circuit breaker opened");
+        inner.state = State::Open;
+        inner.since = Instant::now();
+        inner.consecutive = 0;
+        inner.recent.clear();
+    }
+
+    pub fn state(&self) -> State {
+        self.inner.lock().unwrap().state
+    }
+
+    /// How many connects the breaker has turned away
+    pub fn failed_fast(&self) -> u64 {
+        self.inner.lock().unwrap().failed_fast
+    }
+}
@@ -0,0 +1,479 @@
+use crate::{
+    backends::Affinity,
+    breaker::Thresholds,
+    faults::Faults,
+    mux,
+    net::{Address, Allowlist, Cidrs, Upstreams},
+    transform::{Mode, Transforms},
+    tunnel::Role,
+    Args,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+/// How often the config file is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How bytes are moved between the client and the upstream
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    /// `forward_custom` with a buffer of `buf_size` bytes per direction
+    Custom,
+    /// `tokio::io::copy` per direction
+    TokioCopy,
+    /// `tokio::io::copy_bidirectional`
+    TokioCopyBidirectional,
+}
+
+/// Where a route's connections go
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Destination {
+    /// Always to the `upstream`
+    Fixed,
+    /// Wherever a SOCKS5 client asks to connect to
+    Socks5,
+    /// Wherever an HTTP `CONNECT` request asks to connect to
+    Connect,
+    /// Wherever the client was connecting to before a firewall rule
+    /// redirected it to the proxy
+    Original,
+}
+
+impl FromStr for Destination {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Destination, String> {
+        match s {
+            "fixed" => Ok(Destination::Fixed),
+            "socks5" => Ok(Destination::Socks5),
+            "connect" => Ok(Destination::Connect),
+            "original" => Ok(Destination::Original),
+            _ => Err(format!("Unknown destination {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Destination::Fixed => write!(f, "fixed"),
+            Destination::Socks5 => write!(f, "socks5"),
+            Destination::Connect => write!(f, "connect"),
+            Destination::Original => write!(f, "original"),
+        }
+    }
+}
+
+/// Every route the proxy serves
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub routes: Vec<Route>,
+}
+
+/// One listener and where its connections go. Each connection keeps the
+/// `Route` that was current when it was accepted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Route {
+    pub listen: Address,
+    pub ipv6_only: Option<bool>,
+    pub transparent: bool,
+    /// Clients outside these networks are turned away, when set
+    pub allow_clients: Option<Cidrs>,
+    pub deny_clients: Option<Cidrs>,
+    pub upstream: Upstreams,
+    pub affinity: Affinity,
+    /// How many bytes a client has to send before `Affinity::Prefix` picks
+    /// its backend, unless it stops sending sooner
+    pub affinity_prefix: usize,
+    pub affinity_timeout: Option<Duration>,
+    pub sticky_table_size: usize,
+    pub sticky_ttl: Duration,
+    pub slow_start: Option<Duration>,
+    /// When each upstream's circuit breaker opens; a route without
+    /// thresholds has none
+    pub breaker: Thresholds,
+    /// Connections kept open to each fixed upstream, ready for new clients
+    pub warm_connections: usize,
+    pub warm_max_idle: Option<Duration>,
+    pub destination: Destination,
+    pub socks_users: Option<PathBuf>,
+    /// `None` allows every destination a client picks
+    pub allow_destinations: Option<Allowlist>,
+    pub shadow: Option<Address>,
+    pub strategy: Strategy,
+    pub buf_size: usize,
+    pub resolve_ttl: Duration,
+    pub hosts_file: Option<PathBuf>,
+    pub connect_attempt_delay: Duration,
+    pub connect_timeout: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+    pub faults: Faults,
+    pub transform: Transforms,
+    pub transform_mode: Mode,
+    pub tunnel: Option<Role>,
+    pub tunnel_key: Option<PathBuf>,
+    pub mux: Option<mux::Role>,
+    pub mux_connections: usize,
+}
+
+/// The keys of a `[[route]]` table in the TOML config file. The same keys at
+/// the top level are the defaults for every route, and any key missing from
+/// both falls back to the command-line value, with the same names and units.
+/// Without `[[route]]` tables, the top level describes the only route.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RouteFile {
+    listen: Option<String>,
+    ipv6_only: Option<bool>,
+    transparent: Option<bool>,
+    allow_clients: Option<String>,
+    deny_clients: Option<String>,
+    upstream: Option<String>,
+    affinity: Option<Affinity>,
+    affinity_prefix: Option<usize>,
+    affinity_timeout: Option<u64>,
+    sticky_table_size: Option<usize>,
+    sticky_ttl: Option<u64>,
+    slow_start: Option<u64>,
+    breaker_failures: Option<u32>,
+    breaker_failure_rate: Option<f64>,
+    breaker_window: Option<usize>,
+    breaker_open_for: Option<u64>,
+    warm_connections: Option<usize>,
+    warm_max_idle: Option<u64>,
+    destination: Option<Destination>,
+    socks_users: Option<PathBuf>,
+    allow_destinations: Option<String>,
+    shadow: Option<String>,
+    strategy: Option<Strategy>,
+    buf_size: Option<usize>,
+    resolve_ttl: Option<u64>,
+    hosts_file: Option<PathBuf>,
+    connect_attempt_delay: Option<u64>,
+    connect_timeout: Option<u64>,
+    idle_timeout: Option<u64>,
+    faults: Option<Faults>,
+    transform: Option<String>,
+    transform_mode: Option<Mode>,
+    tunnel: Option<Role>,
+    tunnel_key: Option<PathBuf>,
+    mux: Option<mux::Role>,
+    mux_connections: Option<usize>,
+}
+
+impl Config {
+    pub fn from_args(args: &Args) -> Config {
+        Config {
+            routes: vec![Route::from_args(args)],
+        }
+    }
+
+    /// Reads the config file and applies it on top of the command-line values
+    pub fn load(args: &Args, path: &Path) -> io::Result<Config> {
+        let contents = std::fs::read_to_string(path)?;
+        let mut table: toml::value::Table = toml::from_str(&contents).map_err(invalid_data)?;
+        let routes: Vec<RouteFile> = match table.remove("route") {
+            Some(routes) => routes.try_into().map_err(invalid_data)?,
+            None => Vec::new(),
+        };
+        let defaults: RouteFile = toml::Value::Table(table).try_into().map_err(invalid_data)?;
+
+        let mut base = Route::from_args(args);
+        defaults.apply(&mut base)?;
+        if routes.is_empty() {
+            return Ok(Config { routes: vec![base] });
+        }
+
+        let mut config = Config { routes: Vec::new() };
+        for file in routes {
+            let mut route = base.clone();
+            file.apply(&mut route)?;
+            if config.routes.iter().any(|r| r.listen == route.listen) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("More than one route listens on {}", route.listen),
+                ));
+            }
+            config.routes.push(route);
+        }
+        Ok(config)
+    }
+}
+
+impl Route {
+    fn from_args(args: &Args) -> Route {
+        let strategy = if args.tokio_copy_bi {
+            Strategy::TokioCopyBidirectional
+        } else if args.tokio_copy {
+            Strategy::TokioCopy
+        } else {
+            Strategy::Custom
+        };
+        Route {
+            listen: args.listen.clone(),
+            ipv6_only: args.ipv6_only,
+            transparent: args.transparent,
+            allow_clients: args.allow_clients.clone(),
+            deny_clients: args.deny_clients.clone(),
+            upstream: args.upstream.clone(),
+            affinity: args.affinity,
+            affinity_prefix: args.affinity_prefix,
+            affinity_timeout: non_zero(Duration::from_millis(args.affinity_timeout)),
+            sticky_table_size: args.sticky_table_size,
+            sticky_ttl: Duration::from_secs(args.sticky_ttl),
+            slow_start: non_zero(Duration::from_secs(args.slow_start)),
+            breaker: Thresholds {
+                failures: args.breaker_failures,
+                failure_rate: args.breaker_failure_rate,
+                window: args.breaker_window,
+                open_for: Duration::from_secs(args.breaker_open_for),
+            },
+            warm_connections: args.warm_connections,
+            warm_max_idle: non_zero(Duration::from_secs(args.warm_max_idle)),
+            destination: args.destination,
+            socks_users: args.socks_users.clone(),
+            allow_destinations: args.allow_destinations.clone(),
+            shadow: args.shadow.clone(),
+            strategy,
+            buf_size: args.buf_size,
+            resolve_ttl: Duration::from_secs(args.resolve_ttl),
+            hosts_file: args.hosts_file.clone(),
+            connect_attempt_delay: Duration::from_millis(args.connect_attempt_delay),
+            connect_timeout: non_zero(Duration::from_millis(args.connect_timeout)),
+            idle_timeout: non_zero(Duration::from_secs(args.idle_timeout)),
+            faults: Faults {
+                seed: args.fault_seed,
+                latency: args.fault_latency,
+                latency_probability: args.fault_latency_probability,
+                jitter: args.fault_jitter,
+                jitter_probability: args.fault_jitter_probability,
+                reset_probability: args.fault_reset_probability,
+                drop_after: args.fault_drop_after,
+                drop_probability: args.fault_drop_probability,
+                corrupt_probability: args.fault_corrupt_probability,
+                segment_size: args.fault_segment_size,
+                segment_probability: args.fault_segment_probability,
+            },
+            transform: args.transform.clone().unwrap_or_default(),
+            transform_mode: args.transform_mode,
+            tunnel: args.tunnel,
+            tunnel_key: args.tunnel_key.clone(),
+            mux: args.mux,
+            mux_connections: args.mux_connections,
+        }
+    }
+}
+
+impl Route {
+    /// Whether a client may connect: it must not be on the deny list, and
+    /// must be on the allow list if there is one
+    pub fn admits(&self, client: IpAddr) -> bool {
+        let denied = self
+            .deny_clients
+            .as_ref()
+            .is_some_and(|denied| denied.contains(client));
+        let allowed = self
+            .allow_clients
+            .as_ref()
+            .is_none_or(|allowed| allowed.contains(client));
+        allowed && !denied
+    }
+}
+
+impl RouteFile {
+    fn apply(self, route: &mut Route) -> io::Result<()> {
+        if let Some(listen) = self.listen {
+            route.listen = parse_address(&listen)?;
+        }
+        if let Some(upstream) = self.upstream {
+            route.upstream = upstream
+                .parse()
+                .map_err(|e: String| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        }
+        if let Some(affinity) = self.affinity {
+            route.affinity = affinity;
+        }
+        if let Some(bytes) = self.affinity_prefix {
+            route.affinity_prefix = bytes;
+        }
+        if let Some(timeout) = self.affinity_timeout {
+            route.affinity_timeout = non_zero(Duration::from_millis(timeout));
+        }
+        if let Some(size) = self.sticky_table_size {
+            route.sticky_table_size = size;
+        }
+        if let Some(ttl) = self.sticky_ttl {
+            route.sticky_ttl = Duration::from_secs(ttl);
+        }
+        if let Some(slow_start) = self.slow_start {
+            route.slow_start = non_zero(Duration::from_secs(slow_start));
+        }
+        if let Some(failures) = self.breaker_failures {
+            route.breaker.failures = failures;
+        }
+        if let Some(rate) = self.breaker_failure_rate {
+            route.breaker.failure_rate = rate;
+        }
+        if let Some(window) = self.breaker_window {
+            route.breaker.window = window;
+        }
+        if let Some(open_for) = self.breaker_open_for {
+            route.breaker.open_for = Duration::from_secs(open_for);
+        }
+        if let Some(connections) = self.warm_connections {
+            route.warm_connections = connections;
+        }
+        if let Some(max_idle) = self.warm_max_idle {
+            route.warm_max_idle = non_zero(Duration::from_secs(max_idle));
+        }
+        if let Some(destination) = self.destination {
+            route.destination = destination;
+        }
+        if self.socks_users.is_some() {
+            route.socks_users = self.socks_users;
+        }
+        if let Some(allowed) = self.allow_destinations {
+            route.allow_destinations = Some(
+                allowed
+                    .parse()
+                    .map_err(|e: String| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+            );
+        }
+        if let Some(shadow) = self.shadow {
+            route.shadow = Some(parse_address(&shadow)?);
+        }
+        if self.ipv6_only.is_some() {
+            route.ipv6_only = self.ipv6_only;
+        }
+        if let Some(transparent) = self.transparent {
+            route.transparent = transparent;
+        }
+        if let Some(allowed) = self.allow_clients {
+            route.allow_clients = Some(parse_cidrs(&allowed)?);
+        }
+        if let Some(denied) = self.deny_clients {
+            route.deny_clients = Some(parse_cidrs(&denied)?);
+        }
+        if let Some(strategy) = self.strategy {
+            route.strategy = strategy;
+        }
+        if let Some(buf_size) = self.buf_size {
+            route.buf_size = buf_size;
+        }
+        if let Some(resolve_ttl) = self.resolve_ttl {
+            route.resolve_ttl = Duration::from_secs(resolve_ttl);
+        }
+        if self.hosts_file.is_some() {
+            route.hosts_file = self.hosts_file;
+        }
+        if let Some(delay) = self.connect_attempt_delay {
+            route.connect_attempt_delay = Duration::from_millis(delay);
+        }
+        if let Some(timeout) = self.connect_timeout {
+            route.connect_timeout = non_zero(Duration::from_millis(timeout));
+        }
+        if let Some(timeout) = self.idle_timeout {
+            route.idle_timeout = non_zero(Duration::from_secs(timeout));
+        }
+        if let Some(faults) = self.faults {
+            route.faults = faults;
+        }
+        if let Some(transform) = self.transform {
+            route.transform = transform
+                .parse()
+                .map_err(|e: String| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        }
+        if let Some(mode) = self.transform_mode {
+            route.transform_mode = mode;
+        }
+        if self.tunnel.is_some() {
+            route.tunnel = self.tunnel;
+        }
+        if self.tunnel_key.is_some() {
+            route.tunnel_key = self.tunnel_key;
+        }
+        if self.mux.is_some() {
+            route.mux = self.mux;
+        }
+        if let Some(connections) = self.mux_connections {
+            route.mux_connections = connections;
+        }
+        Ok(())
+    }
+}
+
+fn invalid_data<E>(err: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+fn parse_address(address: &str) -> io::Result<Address> {
+    address
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+fn parse_cidrs(cidrs: &str) -> io::Result<Cidrs> {
+    cidrs
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Zero disables a timeout
+fn non_zero(duration: Duration) -> Option<Duration> {
+    if duration.is_zero() {
+        None
+    } else {
+        Some(duration)
+    }
+}
+
+/// Signals a reload on SIGHUP or when the config file's mtime changes
+pub struct Watcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    hangup: Signal,
+}
+
+impl Watcher {
+    pub fn new(path: &Path) -> io::Result<Watcher> {
+        Ok(Watcher {
+            path: path.to_owned(),
+            modified: modified(path),
+            hangup: signal(SignalKind::hangup())?,
+        })
+    }
+
+    pub async fn changed(&mut self) {
+        loop {
+            tokio::select! {
+                _ = self.hangup.recv() => {
+                    self.modified = modified(&self.path);
+                    return;
+                }
+                _ = tokio::time::sleep(POLL_INTERVAL) => {
+                    let modified = modified(&self.path);
+                    if modified != self.modified {
+                        self.modified = modified;
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
@@ -0,0 +1,151 @@
+use crate::{access_log::Side, net::WriteHalf};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Deserialize;
+use std::{fmt, io, time::Duration};
+use tokio::io::AsyncWriteExt;
+
+/// Faults `forward_custom` injects into the chunks it forwards, for testing
+/// how clients cope. Every fault has its own probability, and all are off by
+/// default. In the config file they are a `faults` table with these keys.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Faults {
+    /// Seeds the random choices, together with the connection id and the
+    /// direction, so a run can be repeated. Unset picks a random seed.
+    pub seed: Option<u64>,
+    /// Milliseconds to wait before writing a chunk
+    pub latency: u64,
+    pub latency_probability: f64,
+    /// Up to this many more milliseconds to wait, uniformly distributed
+    pub jitter: u64,
+    pub jitter_probability: f64,
+    /// Chance per chunk of closing the connection with a TCP reset
+    pub reset_probability: f64,
+    /// Bytes a direction forwards before the connection is closed
+    pub drop_after: u64,
+    /// Chance per direction of closing the connection after `drop_after` bytes
+    pub drop_probability: f64,
+    /// Chance per chunk of flipping the bits of one random byte
+    pub corrupt_probability: f64,
+    /// Bytes per write when a chunk is sliced into tiny segments, at least 1
+    pub segment_size: usize,
+    pub segment_probability: f64,
+}
+
+impl Faults {
+    pub fn is_enabled(&self) -> bool {
+        [
+            self.latency_probability,
+            self.jitter_probability,
+            self.reset_probability,
+            self.drop_probability,
+            self.corrupt_probability,
+            self.segment_probability,
+        ]
+        .iter()
+        .any(|p| *p > 0.0)
+    }
+}
+
+/// A fault that ends the connection
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    Reset,
+    Drop,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::Reset => write!(f, "reset"),
+            Fault::Drop => write!(f, "drop"),
+        }
+    }
+}
+
+/// Applies `Faults` to the chunks of one direction
+pub struct Injector {
+    faults: Faults,
+    rng: StdRng,
+    /// Bytes left before this direction drops the connection, if it does
+    drop_in: Option<u64>,
+}
+
+impl Injector {
+    /// Returns `None` when no fault is enabled, so the forwarder can skip the
+    /// injector entirely
+    pub fn new(faults: &Faults, id: u64, from: Side) -> Option<Injector> {
+        if !faults.is_enabled() {
+            return None;
+        }
+        let seed = faults
+            .seed
+            .map_or_else(rand::random, |seed| seed ^ (id << 1 | from as u64));
+        let mut injector = Injector {
+            faults: faults.clone(),
+            rng: StdRng::seed_from_u64(seed),
+            drop_in: None,
+        };
+        if injector.chance(faults.drop_probability) {
+            injector.drop_in = Some(faults.drop_after);
+        }
+        Some(injector)
+    }
+
+    /// Writes a chunk with the faults applied. Returns the fault that ends
+    /// the connection, if one happened.
+    pub async fn write(
+        &mut self,
+        write: &mut WriteHalf,
+        chunk: &mut [u8],
+    ) -> io::Result<Option<Fault>> {
+        if self.chance(self.faults.reset_probability) {
+            return Ok(Some(Fault::Reset));
+        }
+
+        let mut chunk = chunk;
+        let mut dropped = false;
+        if let Some(left) = self.drop_in {
+            if chunk.len() as u64 >= left {
+                chunk = &mut chunk[..left as usize];
+                dropped = true;
+            } else {
+                self.drop_in = Some(left - chunk.len() as u64);
+            }
+        }
+
+        let mut delay = 0;
+        if self.chance(self.faults.latency_probability) {
+            delay += self.faults.latency;
+        }
+        if self.chance(self.faults.jitter_probability) {
+            delay += self.rng.gen_range(0..=self.faults.jitter);
+        }
+        if delay > 0 {
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+        }
+
+        if !chunk.is_empty() && self.chance(self.faults.corrupt_probability) {
+            let i = self.rng.gen_range(0..chunk.len());
+            chunk[i] ^= self.rng.gen_range(1..=u8::MAX);
+        }
+
+        if self.chance(self.faults.segment_probability) {
+            // Without Nagle's algorithm, every slice leaves as its own segment
+            write.set_nodelay(true)?;
+            for segment in chunk.chunks(self.faults.segment_size.max(1)) {
+                write.write_all(segment).await?;
+                write.flush().await?;
+            }
+        } else {
+            write.write_all(chunk).await?;
+        }
+
+        Ok(if dropped { Some(Fault::Drop) } else { None })
+    }
+
+    /// Out-of-range probabilities are clamped, rather than panicking mid-copy
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.rng.gen_bool(probability.min(1.0))
+    }
+}
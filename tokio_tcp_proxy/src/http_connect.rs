@@ -0,0 +1,142 @@
+use crate::net::{Stream, Target};
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// The longest request head a client may send before it is turned away
+const MAX_HEAD: usize = 8 * 1024;
+
+/// Reads a `CONNECT host:port HTTP/1.1` request and returns where the client
+/// asked to go, along with any bytes it sent after the request without waiting
+/// for the reply. Other requests get their error response before this fails.
+pub async fn accept(stream: &mut Stream) -> io::Result<(Target, Vec<u8>)> {
+    let mut head = Vec::with_capacity(1024);
+    let mut chunk = [0; 1024];
+    let end = loop {
+        // The blank line may have started in the previous chunk
+        let from = head.len().saturating_sub(chunk.len() + 3);
+        if let Some(at) = head[from..].windows(4).position(|w| w == b"\r\n\r\n") {
+            break from + at + 4;
+        }
+        if head.len() >= MAX_HEAD {
+            respond(stream, "431 Request Header Fields Too Large", "").await?;
+            return Err(invalid("CONNECT request head is too long"));
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        head.extend_from_slice(&chunk[..n]);
+    };
+    let early = head.split_off(end);
+
+    let request_line = head
+        .split(|&byte| byte == b'\r')
+        .next()
+        .and_then(|line| std::str::from_utf8(line).ok())
+        .unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let (method, authority, version) = (parts.next(), parts.next(), parts.next());
+    if !version.is_some_and(|version| version.starts_with("HTTP/1.")) || parts.next().is_some() {
+        respond(stream, "400 Bad Request", "").await?;
+        return Err(invalid(format!("Invalid request line {:?}", request_line)));
+    }
+    if method != Some("CONNECT") {
+        respond(stream, "405 Method Not Allowed", "Allow: CONNECT\r\n").await?;
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Unsupported method {}", method.unwrap_or_default()),
+        ));
+    }
+    match authority.unwrap_or_default().parse() {
+        Ok(target) => Ok((target, early)),
+        Err(err) => {
+            respond(stream, "400 Bad Request", "").await?;
+            Err(invalid(err))
+        }
+    }
+}
+
+/// Tells the client whether connecting to its target worked, which starts the
+/// tunnel, or why it failed
+pub async fn reply(
+    stream: &mut Stream,
+    connected: Result<Option<SocketAddr>, io::ErrorKind>,
+) -> io::Result<()> {
+    let status = match connected {
+        Ok(_) => {
+            return stream
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await
+        }
+        Err(io::ErrorKind::PermissionDenied) => "403 Forbidden",
+        Err(io::ErrorKind::TimedOut) => "504 Gateway Timeout",
+        Err(_) => "502 Bad Gateway",
+    };
+    respond(stream, status, "").await
+}
+
+/// Sends an error response, after which the connection closes
+async fn respond(stream: &mut Stream, status: &str, headers: &str) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n",
+        status, headers
+    );
+    stream.write_all(response.as_bytes()).await
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// Reads `prefix` before the bytes of the stream itself, leaving writes
+/// untouched
+pub struct Prefixed<S> {
+    prefix: Vec<u8>,
+    inner: S,
+}
+
+impl<S> Prefixed<S> {
+    pub fn new(prefix: Vec<u8>, inner: S) -> Prefixed<S> {
+        Prefixed { prefix, inner }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Prefixed<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.prefix.is_empty() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let n = this.prefix.len().min(buf.remaining());
+        buf.put_slice(&this.prefix[..n]);
+        this.prefix.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Prefixed<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -1,24 +1,131 @@
+use access_log::AccessLog;
+use backends::Affinity;
 use clap::Clap;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpListener, TcpStream,
-    },
+use config::{Config, Destination, Watcher};
+use net::{Address, Allowlist, Cidrs, Listener, Upstreams};
+use proxy::{Settings, SharedSettings};
+use registry::Registry;
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, RwLock},
 };
+use tap::Tap;
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+use tracing_subscriber::filter::LevelFilter;
+use transform::{Mode, Transforms};
+use tunnel::Role;
 
 #[macro_use]
 extern crate lazy_static;
 
+mod access_log;
+mod admin;
+mod backends;
+mod breaker;
+mod config;
+mod faults;
+mod http_connect;
+mod logging;
+mod mirror;
+mod mux;
+mod net;
+mod pool;
+mod proxy;
+mod registry;
+mod resolver;
+mod socks;
+mod tap;
+mod transform;
+mod tunnel;
+
 /// A simple TCP proxy
 #[derive(Clap, Debug)]
 struct Args {
-    /// The address to listen on
+    /// The address to listen on, `host:port` or `unix:/path`
     #[clap(short, long, default_value = "127.0.0.1:20000")]
-    pub listen: String,
-    /// The address to connect to
+    pub listen: Address,
+    /// The address to connect to, `host:port` or `unix:/path`, or a
+    /// comma-separated list of them to spread connections over. Each can
+    /// have a weight, as in `127.0.0.1:20002=3`.
     #[clap(short, long, default_value = "127.0.0.1:20002")]
-    pub upstream: String,
+    pub upstream: Upstreams,
+    /// What keeps a client on the same upstream when there are several: its
+    /// `client-ip`, the first bytes it sends with `prefix`, or `none`
+    #[clap(long, default_value = "none", possible_values = &["none", "client-ip", "prefix"])]
+    pub affinity: Affinity,
+    /// How many bytes `--affinity prefix` waits for, such as the length of a
+    /// session token at the start of the stream
+    #[clap(long, default_value = "16")]
+    pub affinity_prefix: usize,
+    /// Milliseconds `--affinity prefix` waits for those bytes before it picks
+    /// an upstream with what arrived; 0 waits forever
+    #[clap(long, default_value = "1000")]
+    pub affinity_timeout: u64,
+    /// The most clients to remember the upstream of; the one unused for
+    /// longest makes room for a new one
+    #[clap(long, default_value = "10000")]
+    pub sticky_table_size: usize,
+    /// Seconds a client's upstream is remembered after its last connection
+    #[clap(long, default_value = "300")]
+    pub sticky_ttl: u64,
+    /// Seconds over which an upstream that a reload adds, or that connects
+    /// again after failing, ramps up to its full weight; 0 gives it its full
+    /// share right away
+    #[clap(long, default_value = "0")]
+    pub slow_start: u64,
+    /// Consecutive failed connects to an upstream that open its circuit
+    /// breaker, after which clients fail fast instead of connecting; 0 never
+    /// opens it on a streak
+    #[clap(long, default_value = "0")]
+    pub breaker_failures: u32,
+    /// The share of the last `--breaker-window` connects to an upstream that
+    /// have to fail to open its circuit breaker, such as 0.5; 0 never opens
+    /// it on a rate
+    #[clap(long, default_value = "0")]
+    pub breaker_failure_rate: f64,
+    /// How many recent connects `--breaker-failure-rate` looks at
+    #[clap(long, default_value = "20")]
+    pub breaker_window: usize,
+    /// Seconds an open circuit breaker fails clients fast before it lets one
+    /// connect through to probe the upstream
+    #[clap(long, default_value = "10")]
+    pub breaker_open_for: u64,
+    /// How many connections to keep open to each upstream ahead of the
+    /// clients, so a new client skips the upstream handshake. Each one is
+    /// used once and replaced in the background; 0 connects per client.
+    #[clap(long, default_value = "0")]
+    pub warm_connections: usize,
+    /// Seconds an unused warm connection is kept before it is replaced; 0
+    /// keeps it until the upstream closes it
+    #[clap(long, default_value = "30")]
+    pub warm_max_idle: u64,
+    /// Where connections go: always to the `fixed` upstream, wherever a
+    /// `socks5` client or an HTTP `connect` request asks to connect to, or
+    /// the `original` destination of a connection a firewall rule redirected
+    #[clap(
+        long,
+        default_value = "fixed",
+        possible_values = &["fixed", "socks5", "connect", "original"]
+    )]
+    pub destination: Destination,
+    /// A file of `username:password` lines that SOCKS5 clients have to
+    /// authenticate with. Without it, they need no authentication.
+    #[clap(long)]
+    pub socks_users: Option<PathBuf>,
+    /// The destinations clients may pick, as a comma-separated list of
+    /// `host:port` patterns such as `*.example.com:443` or `10.0.0.1:*`.
+    /// Without it, any destination is allowed.
+    #[clap(long)]
+    pub allow_destinations: Option<Allowlist>,
+    /// A second upstream that gets a copy of what clients send, for trying a
+    /// new version with real traffic. Its responses are thrown away, and it
+    /// is dropped from a connection as soon as it falls behind or fails.
+    #[clap(long)]
+    pub shadow: Option<Address>,
     /// Whether to use tokio copy util or custom implementation
     #[clap(short, long)]
     pub tokio_copy: bool,
@@ -30,66 +137,253 @@ struct Args {
     pub buf_size: usize,
     #[clap(short, long, default_value = "6")]
     pub thread_count: usize,
+    /// Set IPV6_V6ONLY on an IPv6 listen address; `false` makes `[::]` dual-stack
+    #[clap(long)]
+    pub ipv6_only: Option<bool>,
+    /// Set IP_TRANSPARENT on the listener, so it accepts the connections a
+    /// TPROXY rule sends it. Needs CAP_NET_ADMIN.
+    #[clap(long)]
+    pub transparent: bool,
+    /// Only accept clients from these networks, as a comma-separated list
+    /// such as `10.0.0.0/8,fd00::/8`. Without it, every client is accepted.
+    #[clap(long)]
+    pub allow_clients: Option<Cidrs>,
+    /// Turn away clients from these networks, even when they are allowed
+    #[clap(long)]
+    pub deny_clients: Option<Cidrs>,
+    /// Seconds to cache the resolved upstream addresses for; 0 resolves on
+    /// every connection
+    #[clap(long, default_value = "30")]
+    pub resolve_ttl: u64,
+    /// An /etc/hosts-style file that overrides the system resolver for the
+    /// upstream host
+    #[clap(long)]
+    pub hosts_file: Option<PathBuf>,
+    /// Milliseconds to wait on a connect attempt before also trying the next
+    /// resolved upstream address
+    #[clap(long, default_value = "250")]
+    pub connect_attempt_delay: u64,
+    /// Milliseconds to wait for the upstream connection; 0 waits forever
+    #[clap(long, default_value = "0")]
+    pub connect_timeout: u64,
+    /// Seconds without data before the custom implementation closes a
+    /// direction; 0 never times out
+    #[clap(long, default_value = "0")]
+    pub idle_timeout: u64,
+    /// A TOML file with the settings above and any number of routes,
+    /// reloaded on SIGHUP or when it changes. Its values take precedence over
+    /// the command line.
+    #[clap(short, long)]
+    pub config: Option<PathBuf>,
+    /// An address to serve the admin HTTP API on, which lists and kills open
+    /// connections and toggles draining. Without it, open connections are not
+    /// kept track of.
+    #[clap(long)]
+    pub admin_listen: Option<SocketAddr>,
+    /// Seeds the injected faults below, so a run can be repeated
+    #[clap(long)]
+    pub fault_seed: Option<u64>,
+    /// Milliseconds the custom implementation waits before writing a chunk
+    #[clap(long, default_value = "0")]
+    pub fault_latency: u64,
+    #[clap(long, default_value = "0")]
+    pub fault_latency_probability: f64,
+    /// Up to this many more milliseconds to wait before writing a chunk
+    #[clap(long, default_value = "0")]
+    pub fault_jitter: u64,
+    #[clap(long, default_value = "0")]
+    pub fault_jitter_probability: f64,
+    /// Chance per chunk of resetting the connection
+    #[clap(long, default_value = "0")]
+    pub fault_reset_probability: f64,
+    /// Bytes a direction forwards before the connection is dropped
+    #[clap(long, default_value = "0")]
+    pub fault_drop_after: u64,
+    /// Chance per direction of dropping the connection after
+    /// `--fault-drop-after` bytes
+    #[clap(long, default_value = "0")]
+    pub fault_drop_probability: f64,
+    /// Chance per chunk of corrupting one byte
+    #[clap(long, default_value = "0")]
+    pub fault_corrupt_probability: f64,
+    /// Bytes per write when a chunk is sliced into tiny segments
+    #[clap(long, default_value = "1")]
+    pub fault_segment_size: usize,
+    #[clap(long, default_value = "0")]
+    pub fault_segment_probability: f64,
+    /// Transforms for the custom implementation to apply to every chunk, as a
+    /// comma-separated list of gzip, zstd, xor:<key> and checksum
+    #[clap(long)]
+    pub transform: Option<Transforms>,
+    /// `encode` what the client sends and decode what the upstream sends, or
+    /// the other way around with `decode`, for the proxy on the far end
+    #[clap(long, default_value = "encode", possible_values = &["encode", "decode"])]
+    pub transform_mode: Mode,
+    /// Encrypt the hop between two proxies: a `client` encrypts towards its
+    /// upstream, which is a proxy running as the `server`
+    #[clap(long, possible_values = &["client", "server"])]
+    pub tunnel: Option<Role>,
+    /// A file with the tunnel's pre-shared key as 64 hex digits, the same on
+    /// both ends
+    #[clap(long)]
+    pub tunnel_key: Option<PathBuf>,
+    /// Carry connections between two proxies as streams over a few long-lived
+    /// connections: a `client` opens them towards its upstream, which is a
+    /// proxy running as the `server`
+    #[clap(long, possible_values = &["client", "server"])]
+    pub mux: Option<mux::Role>,
+    /// How many connections a multiplexing client spreads its streams over
+    #[clap(long, default_value = "2")]
+    pub mux_connections: usize,
+    /// A file to append one line to per closed connection, or `-` for stdout
+    #[clap(long)]
+    pub access_log: Option<PathBuf>,
+    /// A file to capture every chunk read in either direction to, for
+    /// `tap_replay`. Off by default, as it costs a copy of all traffic.
+    #[clap(long)]
+    pub tap: Option<PathBuf>,
+    /// The most verbose log level to emit: off, error, warn, info, debug or
+    /// trace. Build with the `no-logging` feature to compile logging out.
+    #[clap(long, default_value = "info")]
+    pub log_level: LevelFilter,
+    /// Log as JSON lines or as human-readable text
+    #[clap(long, default_value = "json", possible_values = &["json", "text"])]
+    pub log_format: String,
 }
 
 lazy_static! {
     static ref ARGS: Args = Args::parse();
+    static ref REGISTRY: Registry =
+        Registry::new(ARGS.admin_listen.is_some(), ARGS.access_log.is_some());
+    static ref ACCESS_LOG: AccessLog =
+        AccessLog::open(ARGS.access_log.as_deref()).expect("Failed to open access log");
+    static ref TAP: Tap = Tap::open(ARGS.tap.as_deref()).expect("Failed to open tap file");
 }
 
+fn load_config() -> io::Result<Config> {
+    match &ARGS.config {
+        Some(path) => Config::load(&ARGS, path),
+        None => Ok(Config::from_args(&ARGS)),
+    }
+}
+
+/// A running listener and the settings its new connections get
+struct RouteHandle {
+    settings: SharedSettings,
+    accept_loop: JoinHandle<()>,
+}
+
+/// Listeners are identified by their address, so a reload only rebinds routes
+/// whose listen address changed
+type RouteTable = HashMap<Address, RouteHandle>;
+
 async fn listen() {
-    println!(
-        "listen={}, upstream={}, tokio_copy={}, tokio_copy_bi={}, buf_size={}",
-        &ARGS.listen, &ARGS.upstream, ARGS.tokio_copy, ARGS.tokio_copy_bi, ARGS.buf_size
-    );
-    let listener = TcpListener::bind(&ARGS.listen)
+    if let Some(addr) = ARGS.admin_listen {
+        tokio::spawn(admin::serve(addr));
+    }
+
+    let mut routes = RouteTable::new();
+    let config = load_config().expect("Failed to load config");
+    apply(&mut routes, config)
         .await
-        .expect("Failed to bind to listen address");
+        .expect("Failed to start listening");
 
+    let path = match &ARGS.config {
+        Some(path) => path,
+        None => return std::future::pending().await,
+    };
+    let mut watcher = Watcher::new(path).expect("Failed to watch config file");
     loop {
-        let (socket, _) = listener
-            .accept()
-            .await
-            .expect("Failed to accept a new connection");
-
-        tokio::spawn(async move {
-            match TcpStream::connect(&ARGS.upstream).await {
-                Ok(mut target) => {
-                    if ARGS.tokio_copy_bi {
-                        let mut socket = socket;
-                        let _ = tokio::io::copy_bidirectional(&mut target, &mut socket).await;
-                    } else {
-                        let (mut client_read, mut client_write) = socket.into_split();
-                        let (mut upstream_read, mut upstream_write) = target.into_split();
-                        let upstream_handle = tokio::spawn(async move {
-                            if ARGS.tokio_copy {
-                                let _ =
-                                    tokio::io::copy(&mut client_read, &mut upstream_write).await;
-                            } else {
-                                forward_custom(client_read, upstream_write).await;
-                            }
-                        });
-                        let downstream_handle = tokio::spawn(async move {
-                            if ARGS.tokio_copy {
-                                let _ =
-                                    tokio::io::copy(&mut upstream_read, &mut client_write).await;
-                            } else {
-                                forward_custom(upstream_read, client_write).await;
-                            }
-                        });
-
-                        let _ = upstream_handle.await;
-                        let _ = downstream_handle.await;
-                    }
+        watcher.changed().await;
+        let reloaded = match load_config() {
+            Ok(config) => apply(&mut routes, config).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = reloaded {
+            error!(error = %err, "failed to reload config, keeping the old one");
+        }
+    }
+}
+
+/// Brings the running routes in line with `config`. Listeners and upstreams
+/// are all set up before anything is swapped, so a failed reload leaves the
+/// old routes untouched.
+async fn apply(routes: &mut RouteTable, config: Config) -> io::Result<()> {
+    let mut prepared = Vec::with_capacity(config.routes.len());
+    for route in config.routes {
+        let key = route.listen.clone();
+        let current = routes
+            .get(&key)
+            .map(|handle| Arc::clone(&handle.settings.read().unwrap()));
+        // The old listener would still hold the address while the new one
+        // binds it
+        let rebinds = current.as_ref().is_some_and(|current| {
+            (current.route.ipv6_only, current.route.transparent)
+                != (route.ipv6_only, route.transparent)
+        });
+        if rebinds {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "The socket options of {} only change by removing the route in one reload \
+                     and adding it back in the next",
+                    route.listen
+                ),
+            ));
+        }
+        let listener = match current {
+            Some(_) => None,
+            None => Some(Listener::bind(&route.listen, route.ipv6_only, route.transparent).await?),
+        };
+        let settings = match current {
+            Some(current) if current.route == route => None,
+            _ => {
+                info!(?route, "route configured");
+                Some(Settings::new(route, current.as_deref()).await?)
+            }
+        };
+        prepared.push((key, listener, settings));
+    }
+
+    let mut next = RouteTable::with_capacity(prepared.len());
+    for (key, listener, settings) in prepared {
+        let handle = match (routes.remove(&key), listener) {
+            (Some(handle), _) => {
+                if let Some(settings) = settings {
+                    *handle.settings.write().unwrap() = Arc::new(settings);
                 }
-                Err(_) => {
-                    println!("Failed to connect to upstream.");
+                handle
+            }
+            (None, Some(listener)) => {
+                let settings: SharedSettings = Arc::new(RwLock::new(Arc::new(
+                    settings.expect("New routes always have settings"),
+                )));
+                let accept_loop = tokio::spawn(proxy::accept(listener, Arc::clone(&settings)));
+                RouteHandle {
+                    settings,
+                    accept_loop,
                 }
             }
-        });
+            (None, None) => unreachable!("New routes always have a listener"),
+        };
+        next.insert(key, handle);
+    }
+
+    // Routes missing from the new config stop accepting, while their open
+    // connections run to completion
+    for (listen, handle) in routes.drain() {
+        info!(%listen, "stopped listening");
+        handle.accept_loop.abort();
     }
+    *routes = next;
+    Ok(())
 }
 
 fn main() {
+    logging::init(ARGS.log_level, &ARGS.log_format);
+    lazy_static::initialize(&ACCESS_LOG);
+    lazy_static::initialize(&TAP);
+
     let runtime = if ARGS.thread_count > 1 {
         tokio::runtime::Builder::new_multi_thread()
             .worker_threads(ARGS.thread_count)
@@ -105,13 +399,3 @@ fn main() {
 
     runtime.block_on(listen());
 }
-
-async fn forward_custom(mut read: OwnedReadHalf, mut write: OwnedWriteHalf) {
-    let buf_size = ARGS.buf_size;
-    let mut buf: Vec<u8> = vec![0; buf_size];
-    while let Ok(n) = read.read(&mut buf).await {
-        if n == 0 || write.write_all(&buf[..n]).await.is_err() {
-            break;
-        }
-    }
-}
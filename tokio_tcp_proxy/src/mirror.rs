@@ -0,0 +1,176 @@
+use crate::net::Connector;
+use std::{
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::{
+        mpsc::{self, error::TrySendError},
+        oneshot,
+    },
+};
+use tracing::{debug, info_span, warn, Instrument};
+
+/// Chunks waiting for the shadow upstream before it is dropped as too slow
+const QUEUE: usize = 64;
+
+/// Sends a copy of what the client sends to a shadow upstream, and throws its
+/// responses away. Chunks are only ever handed over with `try_send`, so a slow
+/// or failing shadow loses its copy of the connection rather than holding up
+/// the real one.
+pub struct Mirror {
+    chunks: Option<mpsc::Sender<Vec<u8>>>,
+    lagged: Option<oneshot::Sender<()>>,
+}
+
+impl Mirror {
+    /// Without a shadow, nothing is mirrored
+    pub fn start(shadow: Option<&Arc<Connector>>, connect_timeout: Option<Duration>) -> Mirror {
+        let shadow = match shadow {
+            Some(shadow) => shadow,
+            None => {
+                return Mirror {
+                    chunks: None,
+                    lagged: None,
+                }
+            }
+        };
+        let (chunks, received) = mpsc::channel(QUEUE);
+        let (lagged, lag) = oneshot::channel();
+        tokio::spawn(
+            forward(Arc::clone(shadow), connect_timeout, received, lag)
+                .instrument(info_span!("shadow")),
+        );
+        Mirror {
+            chunks: Some(chunks),
+            lagged: Some(lagged),
+        }
+    }
+
+    pub fn send(&mut self, data: &[u8]) {
+        if let Some(chunks) = &self.chunks {
+            match chunks.try_send(data.to_vec()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    if let Some(lagged) = self.lagged.take() {
+                        let _ = lagged.send(());
+                    }
+                    self.chunks = None;
+                }
+                // The shadow failed, and said so already
+                Err(TrySendError::Closed(_)) => self.chunks = None,
+            }
+        }
+    }
+}
+
+/// Writes the mirrored chunks to the shadow until the client is done, while
+/// a separate task drains the responses. Falling behind ends it straight
+/// away, even while it is stuck on a write.
+async fn forward(
+    connector: Arc<Connector>,
+    connect_timeout: Option<Duration>,
+    mut chunks: mpsc::Receiver<Vec<u8>>,
+    mut lag: oneshot::Receiver<()>,
+) {
+    let connect = connector.connect();
+    let connected = match connect_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, connect)
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+        None => connect.await,
+    };
+    let (mut read, mut write) = match connected {
+        Ok(shadow) => shadow.into_split(),
+        Err(err) => {
+            warn!(error = %err, "failed to connect to shadow");
+            return;
+        }
+    };
+    let drain = tokio::spawn(async move {
+        tokio::io::copy(&mut read, &mut tokio::io::sink())
+            .await
+            .unwrap_or(0)
+    });
+
+    let written = async {
+        while let Some(chunk) = chunks.recv().await {
+            write.write_all(&chunk).await?;
+        }
+        Ok::<_, io::Error>(())
+    };
+    let lagged = tokio::select! {
+        written = written => match written {
+            Ok(()) => lag.try_recv().is_ok(),
+            Err(err) => {
+                warn!(error = %err, "failed to write to shadow");
+                drain.abort();
+                return;
+            }
+        },
+        Ok(()) = &mut lag => true,
+    };
+
+    // A shadow that missed chunks gets a reset, not a clean end of a stream
+    // it never saw all of
+    if lagged {
+        warn!("shadow fell behind, dropped it");
+        drain.abort();
+        write.reset();
+        return;
+    }
+    let _ = write.shutdown().await;
+    let discarded = drain.await.unwrap_or(0);
+    debug!(discarded, "shadow closed");
+}
+
+/// Mirrors the bytes read through a stream, leaving writes untouched
+pub struct Mirrored<S> {
+    inner: S,
+    mirror: Mirror,
+}
+
+impl<S> Mirrored<S> {
+    pub fn new(inner: S, mirror: Mirror) -> Mirrored<S> {
+        Mirrored { inner, mirror }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Mirrored<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        let read = &buf.filled()[before..];
+        if !read.is_empty() {
+            this.mirror.send(read);
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Mirrored<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
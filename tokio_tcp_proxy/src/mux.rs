@@ -0,0 +1,576 @@
+use crate::{
+    net::{Connector, ReadHalf, Stream, WriteHalf},
+    proxy::{self, Settings},
+    REGISTRY,
+};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream, ReadBuf},
+    sync::{mpsc, Semaphore},
+};
+use tracing::{debug, info_span, warn, Instrument};
+
+/// Bytes a stream may have in flight before the receiver hands back credit
+const WINDOW: usize = 256 * 1024;
+
+/// The largest payload of a data frame
+const MAX_DATA: usize = 32 * 1024;
+
+/// A frame kind, then the stream id and a length or credit, all big-endian
+const HEADER: usize = 9;
+
+/// How many bytes of queued frames the writer gathers into one write
+const MAX_BATCH: usize = 64 * 1024;
+
+/// The length of an IPv4 and an IPv6 client address in an `Open` frame
+const ADDR_V4: usize = 1 + 4 + 2;
+const ADDR_V6: usize = 1 + 16 + 2;
+
+/// Which end of a pair of multiplexing proxies this one is
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Carries its clients' connections as streams over a few long-lived
+    /// connections to its upstream
+    Client,
+    /// Accepts multiplexed connections, and opens an upstream connection per
+    /// stream
+    Server,
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Role, String> {
+        match s {
+            "client" => Ok(Role::Client),
+            "server" => Ok(Role::Server),
+            _ => Err(format!("Unknown multiplexing role {}", s)),
+        }
+    }
+}
+
+/// Frames on a multiplexed connection. Only the client opens streams, naming
+/// the address of the client the stream is for when it has one; either end
+/// can send data, half-close with `Fin`, or abort with `Reset`. Every data
+/// byte uses up credit that the receiver hands back with `Window` once it has
+/// passed the byte on, so a slow stream cannot hold up the others.
+#[derive(Debug, PartialEq)]
+enum Frame {
+    Open(u32, Option<SocketAddr>),
+    Data(u32, Vec<u8>),
+    Window(u32, u32),
+    Fin(u32),
+    Reset(u32),
+}
+
+impl Frame {
+    fn encode(&self, out: &mut Vec<u8>) {
+        let client;
+        let (kind, id, value, data): (u8, u32, u32, &[u8]) = match self {
+            Frame::Open(id, addr) => {
+                client = encode_addr(*addr);
+                (0, *id, client.len() as u32, &client)
+            }
+            Frame::Data(id, data) => (1, *id, data.len() as u32, data),
+            Frame::Window(id, credit) => (2, *id, *credit, &[]),
+            Frame::Fin(id) => (3, *id, 0, &[]),
+            Frame::Reset(id) => (4, *id, 0, &[]),
+        };
+        out.push(kind);
+        out.extend_from_slice(&id.to_be_bytes());
+        out.extend_from_slice(&value.to_be_bytes());
+        out.extend_from_slice(data);
+    }
+
+    async fn read(read: &mut (impl AsyncRead + Unpin)) -> io::Result<Frame> {
+        let mut header = [0; HEADER];
+        read.read_exact(&mut header).await?;
+        let id = u32::from_be_bytes(header[1..5].try_into().unwrap());
+        let value = u32::from_be_bytes(header[5..9].try_into().unwrap());
+        match header[0] {
+            0 if value as usize <= ADDR_V6 => {
+                let mut client = vec![0; value as usize];
+                read.read_exact(&mut client).await?;
+                Ok(Frame::Open(id, decode_addr(&client)?))
+            }
+            1 if value as usize <= MAX_DATA => {
+                let mut data = vec![0; value as usize];
+                read.read_exact(&mut data).await?;
+                Ok(Frame::Data(id, data))
+            }
+            2 => Ok(Frame::Window(id, value)),
+            3 => Ok(Frame::Fin(id)),
+            4 => Ok(Frame::Reset(id)),
+            kind => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Invalid multiplexing frame kind {} of {} bytes",
+                    kind, value
+                ),
+            )),
+        }
+    }
+}
+
+/// A client address in an `Open` frame: nothing when there is none, or 4
+/// for IPv4 and 6 for IPv6, then the address and the port
+fn encode_addr(addr: Option<SocketAddr>) -> Vec<u8> {
+    let mut out = Vec::with_capacity(ADDR_V6);
+    match addr.map(|addr| (addr.ip(), addr.port())) {
+        Some((IpAddr::V4(ip), port)) => {
+            out.push(4);
+            out.extend_from_slice(&ip.octets());
+            out.extend_from_slice(&port.to_be_bytes());
+        }
+        Some((IpAddr::V6(ip), port)) => {
+            out.push(6);
+            out.extend_from_slice(&ip.octets());
+            out.extend_from_slice(&port.to_be_bytes());
+        }
+        None => {}
+    }
+    out
+}
+
+fn decode_addr(data: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let ip = match (data.first(), data.len()) {
+        (None, _) => return Ok(None),
+        (Some(4), ADDR_V4) => {
+            let octets: [u8; 4] = data[1..5].try_into().unwrap();
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        (Some(6), ADDR_V6) => {
+            let octets: [u8; 16] = data[1..17].try_into().unwrap();
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid client address in a multiplexing frame",
+            ))
+        }
+    };
+    let port = u16::from_be_bytes(data[data.len() - 2..].try_into().unwrap());
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+/// What the reader passes on to a stream
+enum Incoming {
+    Data(Vec<u8>),
+    Fin,
+    Reset,
+}
+
+struct Slot {
+    incoming: mpsc::UnboundedSender<Incoming>,
+    /// Bytes this end may still send
+    credits: Arc<Semaphore>,
+}
+
+/// One multiplexed connection. A writer task owns the write half, and every
+/// stream is a duplex pipe whose far end the proxy forwards like any socket.
+struct Session {
+    frames: mpsc::UnboundedSender<Frame>,
+    streams: Mutex<HashMap<u32, Slot>>,
+    next_id: AtomicU32,
+    closed: AtomicBool,
+}
+
+impl Session {
+    /// Starts the writer. The caller runs the reader, which only holds on to
+    /// the session weakly, so the connection closes once the caller and the
+    /// streams let go of it. A server lets go when the connection ends; a
+    /// `MuxClient` keeps its connections for new streams, even while idle,
+    /// until a reload replaces it.
+    fn start(connection: Stream) -> (Arc<Session>, BufReader<ReadHalf>) {
+        let (read, write) = connection.into_split();
+        let _ = write.set_nodelay(true);
+        let (frames, queued) = mpsc::unbounded_channel();
+        tokio::spawn(write_frames(write, queued).in_current_span());
+        let session = Arc::new(Session {
+            frames,
+            streams: Mutex::new(HashMap::new()),
+            next_id: AtomicU32::new(1),
+            closed: AtomicBool::new(false),
+        });
+        (session, BufReader::new(read))
+    }
+
+    fn send(&self, frame: Frame) {
+        let _ = self.frames.send(frame);
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    fn open(self: &Arc<Self>, client: Option<SocketAddr>) -> io::Result<Stream> {
+        if self.is_closed() {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "Multiplexed connection closed",
+            ));
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let stream = self.register(id);
+        self.send(Frame::Open(id, client));
+        Ok(stream)
+    }
+
+    fn register(self: &Arc<Self>, id: u32) -> Stream {
+        let (sent, write) = tokio::io::duplex(MAX_DATA);
+        let (delivered, read) = tokio::io::duplex(MAX_DATA);
+        let (incoming, received) = mpsc::unbounded_channel();
+        let credits = Arc::new(Semaphore::new(WINDOW));
+        self.streams.lock().unwrap().insert(
+            id,
+            Slot {
+                incoming,
+                credits: Arc::clone(&credits),
+            },
+        );
+        tokio::spawn(Arc::clone(self).pump(id, sent, delivered, received, credits));
+        Stream::Mux(MuxStream { read, write })
+    }
+
+    /// Moves one stream's bytes between its pipes and the connection
+    async fn pump(
+        self: Arc<Self>,
+        id: u32,
+        mut read: DuplexStream,
+        mut write: DuplexStream,
+        mut received: mpsc::UnboundedReceiver<Incoming>,
+        credits: Arc<Semaphore>,
+    ) {
+        let outbound = async {
+            let mut buf = vec![0; MAX_DATA];
+            while let Ok(n) = read.read(&mut buf).await {
+                if n == 0 {
+                    break;
+                }
+                match credits.acquire_many(n as u32).await {
+                    Ok(permit) => permit.forget(),
+                    // Closed by a reset
+                    Err(_) => return,
+                }
+                self.send(Frame::Data(id, buf[..n].to_vec()));
+            }
+            self.send(Frame::Fin(id));
+        };
+        // Whether the stream was reset
+        let inbound = async {
+            while let Some(incoming) = received.recv().await {
+                match incoming {
+                    Incoming::Data(data) => {
+                        if write.write_all(&data).await.is_err() {
+                            self.send(Frame::Reset(id));
+                            return true;
+                        }
+                        self.send(Frame::Window(id, data.len() as u32));
+                    }
+                    Incoming::Fin => {
+                        let _ = write.shutdown().await;
+                        return false;
+                    }
+                    Incoming::Reset => return true,
+                }
+            }
+            true
+        };
+        tokio::pin!(outbound, inbound);
+
+        // After a reset, dropping both pipes closes the stream for the proxy
+        tokio::select! {
+            _ = &mut outbound => {
+                inbound.await;
+            }
+            reset = &mut inbound => {
+                if !reset {
+                    outbound.await;
+                }
+            }
+        }
+        self.streams.lock().unwrap().remove(&id);
+    }
+
+    /// Dispatches frames until the connection ends, then resets every stream
+    /// still open. A server passes each new stream to `accept`, with the
+    /// client it is for. Opening a stream that is still open ends the
+    /// connection, as the two ends no longer agree on which streams there are.
+    async fn read_frames(
+        session: Weak<Session>,
+        mut read: BufReader<ReadHalf>,
+        accept: Option<&(dyn Fn(Stream, Option<SocketAddr>) + Sync)>,
+    ) {
+        let result = loop {
+            let frame = match Frame::read(&mut read).await {
+                Ok(frame) => frame,
+                Err(err) => break err,
+            };
+            let session = match session.upgrade() {
+                Some(session) => session,
+                None => return,
+            };
+            match frame {
+                Frame::Open(id, _) if session.streams.lock().unwrap().contains_key(&id) => {
+                    break io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Multiplexed stream {} opened twice", id),
+                    );
+                }
+                Frame::Open(id, client) => match accept {
+                    Some(accept) => accept(session.register(id), client),
+                    None => session.send(Frame::Reset(id)),
+                },
+                Frame::Data(id, data) => match session.streams.lock().unwrap().get(&id) {
+                    Some(slot) => {
+                        let _ = slot.incoming.send(Incoming::Data(data));
+                    }
+                    None => session.send(Frame::Reset(id)),
+                },
+                Frame::Window(id, credit) => {
+                    if let Some(slot) = session.streams.lock().unwrap().get(&id) {
+                        slot.credits.add_permits(credit as usize);
+                    }
+                }
+                Frame::Fin(id) => {
+                    if let Some(slot) = session.streams.lock().unwrap().get(&id) {
+                        let _ = slot.incoming.send(Incoming::Fin);
+                    }
+                }
+                Frame::Reset(id) => {
+                    if let Some(slot) = session.streams.lock().unwrap().remove(&id) {
+                        slot.credits.close();
+                        let _ = slot.incoming.send(Incoming::Reset);
+                    }
+                }
+            }
+        };
+
+        if let Some(session) = session.upgrade() {
+            session.closed.store(true, Ordering::Relaxed);
+            let streams: Vec<_> = session.streams.lock().unwrap().drain().collect();
+            debug!(error = %result, streams = streams.len(), "multiplexed connection closed");
+            for (_, slot) in streams {
+                slot.credits.close();
+                let _ = slot.incoming.send(Incoming::Reset);
+            }
+        }
+    }
+}
+
+/// Writes queued frames, gathering whatever is waiting into one write. Ends
+/// the connection once every sender is gone.
+async fn write_frames(mut write: WriteHalf, mut queued: mpsc::UnboundedReceiver<Frame>) {
+    let mut out = Vec::new();
+    while let Some(frame) = queued.recv().await {
+        out.clear();
+        frame.encode(&mut out);
+        while out.len() < MAX_BATCH {
+            match queued.try_recv() {
+                Ok(frame) => frame.encode(&mut out),
+                Err(_) => break,
+            }
+        }
+        if write.write_all(&out).await.is_err() {
+            return;
+        }
+    }
+    let _ = write.shutdown().await;
+}
+
+/// One stream of a multiplexed connection, as the proxy sees it. Each
+/// direction has a pipe of its own, so dropping the write half ends the stream
+/// the way a FIN would.
+pub struct MuxStream {
+    read: DuplexStream,
+    write: DuplexStream,
+}
+
+impl MuxStream {
+    pub fn into_split(self) -> (DuplexStream, DuplexStream) {
+        (self.read, self.write)
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().read).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().write).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().write).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().write).poll_shutdown(cx)
+    }
+}
+
+/// The client end: opens streams round-robin over a fixed number of
+/// connections to the upstream, each made when it is first needed and made
+/// again after it closes
+pub struct MuxClient {
+    sessions: Vec<tokio::sync::Mutex<Option<Arc<Session>>>>,
+    next: AtomicUsize,
+}
+
+impl MuxClient {
+    pub fn new(connections: usize) -> MuxClient {
+        MuxClient {
+            sessions: (0..connections.max(1))
+                .map(|_| tokio::sync::Mutex::new(None))
+                .collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Opens a stream for `client`, whose address the server end sees as the
+    /// stream's client
+    pub async fn open(
+        &self,
+        connector: &Connector,
+        client: Option<SocketAddr>,
+    ) -> io::Result<Stream> {
+        let slot = &self.sessions[self.next.fetch_add(1, Ordering::Relaxed) % self.sessions.len()];
+        let mut slot = slot.lock().await;
+        if let Some(session) = slot.as_ref().filter(|session| !session.is_closed()) {
+            return session.open(client);
+        }
+
+        let (session, read) = Session::start(connector.connect().await?);
+        debug!("multiplexed connection opened");
+        let reader = Session::read_frames(Arc::downgrade(&session), read, None);
+        tokio::spawn(reader.instrument(info_span!("mux")));
+        *slot = Some(Arc::clone(&session));
+        session.open(client)
+    }
+}
+
+/// The server end: every stream on an accepted connection is proxied like a
+/// connection of its own, from the client the other end named. Streams
+/// without one, such as those of Unix socket clients, are from `peer`. The
+/// client has to pass the allow and deny lists too, and is trusted to be who
+/// the admitted peer says it is.
+pub async fn serve(connection: Stream, peer: Option<SocketAddr>, settings: Arc<Settings>) {
+    let span = info_span!("mux", ?peer);
+    let (session, read) = span.in_scope(|| Session::start(connection));
+    let accept = |stream, client: Option<SocketAddr>| {
+        let client = client.or(peer);
+        if REGISTRY.is_draining() {
+            return;
+        }
+        // Dropping the stream ends it straight away
+        if let Some(client) = client.filter(|client| !settings.route.admits(client.ip())) {
+            let rejected = REGISTRY.reject();
+            warn!(%client, listen = %settings.route.listen, rejected, "client rejected");
+            return;
+        }
+        tokio::spawn(proxy::handle(stream, client, Arc::clone(&settings)));
+    };
+    Session::read_frames(Arc::downgrade(&session), read, Some(&accept))
+        .instrument(span)
+        .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn frames_round_trip() {
+        let frames = vec![
+            Frame::Open(1, None),
+            Frame::Open(2, Some("192.0.2.1:4000".parse().unwrap())),
+            Frame::Open(3, Some("[2001:db8::1]:4000".parse().unwrap())),
+            Frame::Data(1, b"hello".to_vec()),
+            Frame::Data(1, vec![7; MAX_DATA]),
+            Frame::Window(2, WINDOW as u32),
+            Frame::Fin(3),
+            Frame::Reset(u32::MAX),
+        ];
+        let mut encoded = Vec::new();
+        for frame in &frames {
+            frame.encode(&mut encoded);
+        }
+        let mut read = &encoded[..];
+        for frame in frames {
+            assert_eq!(Frame::read(&mut read).await.unwrap(), frame);
+        }
+        assert!(read.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_frames() {
+        let mut oversized = Vec::new();
+        Frame::Data(1, vec![0; MAX_DATA + 1]).encode(&mut oversized);
+        let mut unknown = Vec::new();
+        Frame::Fin(1).encode(&mut unknown);
+        unknown[0] = 9;
+        let bad_addr = [0, 0, 0, 0, 1, 0, 0, 0, 3, 5, 1, 2];
+        for frame in [&oversized[..], &unknown, &bad_addr] {
+            let err = Frame::read(&mut &frame[..]).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[tokio::test]
+    async fn opening_a_live_stream_twice_ends_the_connection() {
+        let (to_session, incoming) = tokio::io::duplex(4096);
+        let (outgoing, _from_session) = tokio::io::duplex(4096);
+        let connection = Stream::Mux(MuxStream {
+            read: incoming,
+            write: outgoing,
+        });
+        let (session, read) = Session::start(connection);
+        let accepted = Mutex::new(Vec::new());
+        let accept = |stream, _| accepted.lock().unwrap().push(stream);
+        let reader = Session::read_frames(Arc::downgrade(&session), read, Some(&accept));
+
+        let mut frames = Vec::new();
+        Frame::Open(1, None).encode(&mut frames);
+        Frame::Open(1, None).encode(&mut frames);
+        let mut to_session = to_session;
+        to_session.write_all(&frames).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), reader)
+            .await
+            .expect("the connection stays open");
+        assert!(session.is_closed());
+
+        let mut accepted = accepted.into_inner().unwrap();
+        assert_eq!(accepted.len(), 1);
+        // The first stream is reset along with the connection
+        let mut buf = [0; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), accepted[0].read(&mut buf));
+        assert_eq!(read.await.unwrap().unwrap(), 0);
+    }
+}
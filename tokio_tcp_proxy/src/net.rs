@@ -0,0 +1,658 @@
+use crate::{
+    mux::MuxStream,
+    resolver::{self, Resolver},
+};
+use socket2::{Domain, SockAddr, SockRef, Socket, Type};
+use std::{
+    fmt, io,
+    mem::MaybeUninit,
+    net::{IpAddr, SocketAddr},
+    os::unix::{fs::FileTypeExt, io::AsRawFd},
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf},
+    net::{tcp, unix, TcpListener, TcpStream, UnixListener, UnixStream},
+};
+
+const UNIX_PREFIX: &str = "unix:";
+
+/// A listen or upstream address: `host:port` for TCP or `unix:/path` for a
+/// Unix domain socket
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Address {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix(UNIX_PREFIX) {
+            Some("") => Err(format!("Missing socket path in {}", s)),
+            Some(path) => Ok(Address::Unix(PathBuf::from(path))),
+            None => Ok(Address::Tcp(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Tcp(addr) => write!(f, "{}", addr),
+            Address::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+/// An upstream address and its share of the connections, as `address` or
+/// `address=weight`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Upstream {
+    pub address: Address,
+    pub weight: u32,
+}
+
+impl FromStr for Upstream {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Upstream, String> {
+        let (address, weight) = match s.rsplit_once('=') {
+            Some((address, weight)) => {
+                let weight = weight
+                    .parse()
+                    .map_err(|_| format!("Invalid weight in {}", s))?;
+                (address, weight)
+            }
+            None => (s, 1),
+        };
+        Ok(Upstream {
+            address: address.parse()?,
+            weight,
+        })
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.weight {
+            1 => write!(f, "{}", self.address),
+            weight => write!(f, "{}={}", self.address, weight),
+        }
+    }
+}
+
+/// One or more upstreams, comma-separated. A weight of 0 takes an upstream
+/// out of the rotation, but not all of them can be 0.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Upstreams(pub Vec<Upstream>);
+
+impl FromStr for Upstreams {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Upstreams, String> {
+        let upstreams: Vec<Upstream> = s
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()?;
+        if upstreams.iter().all(|upstream| upstream.weight == 0) {
+            return Err("Expected at least one upstream with a weight above 0".to_owned());
+        }
+        Ok(Upstreams(upstreams))
+    }
+}
+
+impl fmt::Display for Upstreams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, upstream) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}", upstream)?;
+        }
+        Ok(())
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Binds the listener. `ipv6_only` sets `IPV6_V6ONLY` on IPv6 addresses,
+    /// so `[::]` can serve both families, and is left to the OS when `None`.
+    /// `transparent` sets `IP_TRANSPARENT`, so the listener can accept
+    /// connections a `TPROXY` rule hands it for addresses that are not local.
+    pub async fn bind(
+        address: &Address,
+        ipv6_only: Option<bool>,
+        transparent: bool,
+    ) -> io::Result<Listener> {
+        match address {
+            Address::Tcp(addr) => {
+                let address = tokio::net::lookup_host(addr).await?.next().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "No address to listen on")
+                })?;
+                let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+                if let (true, Some(only_v6)) = (address.is_ipv6(), ipv6_only) {
+                    socket.set_only_v6(only_v6)?;
+                }
+                if transparent {
+                    socket.set_ip_transparent(true)?;
+                }
+                socket.set_reuse_address(true)?;
+                socket.set_nonblocking(true)?;
+                socket.bind(&address.into())?;
+                socket.listen(1024)?;
+                TcpListener::from_std(socket.into()).map(Listener::Tcp)
+            }
+            Address::Unix(path) => {
+                remove_stale_socket(path)?;
+                UnixListener::bind(path).map(Listener::Unix)
+            }
+        }
+    }
+
+    /// Accepts a new connection. The peer address is only known for TCP.
+    pub async fn accept(&self) -> io::Result<(Stream, Option<SocketAddr>)> {
+        match self {
+            Listener::Tcp(listener) => listener
+                .accept()
+                .await
+                .map(|(stream, peer)| (Stream::Tcp(stream), Some(peer))),
+            Listener::Unix(listener) => listener
+                .accept()
+                .await
+                .map(|(stream, _)| (Stream::Unix(stream), None)),
+        }
+    }
+}
+
+/// Asks connection tracking for the destination the connection had before
+/// NAT rewrote it
+fn nat_destination(stream: &TcpStream, ipv6: bool) -> io::Result<SocketAddr> {
+    let (level, name) = if ipv6 {
+        (libc::SOL_IPV6, libc::IP6T_SO_ORIGINAL_DST)
+    } else {
+        (libc::SOL_IP, libc::SO_ORIGINAL_DST)
+    };
+    let fd = stream.as_raw_fd();
+    // Safety: getsockopt writes at most `len` bytes of address into the
+    // storage socket2 provides, and sets `len` to what it wrote
+    let ((), address) = unsafe {
+        SockAddr::init(|storage, len| {
+            if libc::getsockopt(fd, level, name, storage.cast(), len) == 0 {
+                Ok(())
+            } else {
+                Err(io::Error::last_os_error())
+            }
+        })
+    }?;
+    address.as_socket().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Original destination is not an IP address",
+        )
+    })
+}
+
+/// A socket file left behind by a previous run would make the bind fail
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    /// One logical stream of a multiplexed connection
+    Mux(MuxStream),
+}
+
+/// Opens connections to the upstream. TCP upstreams go through a
+/// `Resolver`, so their names are not looked up on every connection.
+pub enum Connector {
+    Tcp {
+        resolver: Arc<Resolver>,
+        attempt_delay: Duration,
+    },
+    Unix(PathBuf),
+}
+
+impl Connector {
+    pub async fn new(
+        address: &Address,
+        resolve_ttl: Duration,
+        hosts_file: Option<PathBuf>,
+        attempt_delay: Duration,
+    ) -> io::Result<Connector> {
+        match address {
+            Address::Tcp(addr) => Ok(Connector::Tcp {
+                resolver: Resolver::new(addr, resolve_ttl, hosts_file).await?,
+                attempt_delay,
+            }),
+            Address::Unix(path) => Ok(Connector::Unix(path.clone())),
+        }
+    }
+
+    pub async fn connect(&self) -> io::Result<Stream> {
+        match self {
+            Connector::Tcp {
+                resolver,
+                attempt_delay,
+            } => {
+                let addrs = resolver.resolve().await?;
+                resolver::connect_any(&addrs, *attempt_delay)
+                    .await
+                    .map(Stream::Tcp)
+            }
+            Connector::Unix(path) => UnixStream::connect(path).await.map(Stream::Unix),
+        }
+    }
+}
+
+/// A `host:port` that a client asked to connect to, instead of the route's
+/// upstream
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Target {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Target {
+    /// Looks the host up on every connection, as there is no one name to
+    /// cache
+    pub async fn connect(
+        &self,
+        hosts_file: Option<&Path>,
+        attempt_delay: Duration,
+    ) -> io::Result<Stream> {
+        let addrs = resolver::lookup(&self.host, self.port, hosts_file).await?;
+        resolver::connect_any(&addrs, attempt_delay)
+            .await
+            .map(Stream::Tcp)
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Target, String> {
+        let (host, port) = resolver::split_host_port(s).map_err(|err| err.to_string())?;
+        if host.is_empty() {
+            return Err(format!("Missing host in {}", s));
+        }
+        Ok(Target { host, port })
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// The destinations clients may pick, as a comma-separated list of
+/// `host:port` patterns. A host of `*` matches any host and `*.example.com`
+/// any of its subdomains, and a port of `*` matches any port.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Allowlist(Vec<Allowed>);
+
+#[derive(Clone, Debug, PartialEq)]
+struct Allowed {
+    /// Lowercase, like the hosts it is matched against
+    host: String,
+    /// `None` for any port
+    port: Option<u16>,
+}
+
+impl Allowlist {
+    pub fn allows(&self, target: &Target) -> bool {
+        let host = target.host.to_ascii_lowercase();
+        self.0.iter().any(|allowed| {
+            let port = allowed.port.is_none_or(|port| port == target.port);
+            let host = match allowed.host.strip_prefix('*') {
+                Some("") => true,
+                Some(suffix) => host.ends_with(suffix),
+                // `::1` and `0:0:0:0:0:0:0:1` are the same address
+                None => match (allowed.host.parse::<IpAddr>(), host.parse::<IpAddr>()) {
+                    (Ok(allowed), Ok(ip)) => allowed == ip,
+                    _ => allowed.host == host,
+                },
+            };
+            port && host
+        })
+    }
+}
+
+impl FromStr for Allowlist {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Allowlist, String> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(|pattern| {
+                let invalid = || format!("Expected a host:port pattern, got {}", pattern);
+                let (host, port) = pattern.rsplit_once(':').ok_or_else(invalid)?;
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                // `*.` alone would be the subdomains of nothing, and match any
+                // dotted host
+                let valid = match host.strip_prefix('*') {
+                    None => !host.is_empty(),
+                    Some(suffix) => suffix.is_empty() || (suffix.starts_with('.') && suffix != "."),
+                };
+                if !valid {
+                    return Err(invalid());
+                }
+                let port = match port {
+                    "*" => None,
+                    port => Some(port.parse().map_err(|_| invalid())?),
+                };
+                Ok(Allowed {
+                    host: host.to_ascii_lowercase(),
+                    port,
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map(Allowlist)
+    }
+}
+
+/// An IP network such as `10.0.0.0/8` or `fd00::/8`. A bare address is a
+/// network of one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Dual-stack listeners see IPv4 clients as IPv4-mapped IPv6 addresses
+        let (network, ip, bits) = match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                (u32::from(network) as u128, u32::from(ip) as u128, 32)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => (u128::from(network), u128::from(ip), 128),
+            _ => return false,
+        };
+        (network ^ ip)
+            .checked_shr(bits - self.prefix as u32)
+            .unwrap_or(0)
+            == 0
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let invalid = || {
+            format!(
+                "Expected an address or a network such as 10.0.0.0/8, got {}",
+                s
+            )
+        };
+        let (network, prefix) = match s.split_once('/') {
+            Some((network, prefix)) => (network, Some(prefix)),
+            None => (s, None),
+        };
+        let network: IpAddr = network.parse().map_err(|_| invalid())?;
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|&prefix| prefix <= bits)
+                .ok_or_else(invalid)?,
+            None => bits,
+        };
+        Ok(Cidr { network, prefix })
+    }
+}
+
+/// A comma-separated list of networks
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cidrs(Vec<Cidr>);
+
+impl Cidrs {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+impl FromStr for Cidrs {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidrs, String> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map(Cidrs)
+    }
+}
+
+impl Stream {
+    /// Whether an idle connection is still open. Peeks without waiting, so a
+    /// peer that closed it shows as the end of the stream, while one that
+    /// sent a greeting keeps it for the client.
+    pub fn is_open(&self) -> bool {
+        let socket = match self {
+            Stream::Tcp(stream) => SockRef::from(stream),
+            Stream::Unix(stream) => SockRef::from(stream),
+            Stream::Mux(_) => return true,
+        };
+        match socket.peek(&mut [MaybeUninit::uninit()]) {
+            Ok(0) => false,
+            Ok(_) => true,
+            Err(err) => err.kind() == io::ErrorKind::WouldBlock,
+        }
+    }
+
+    /// The local address of a TCP connection
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Stream::Tcp(stream) => stream.local_addr().ok(),
+            Stream::Unix(_) | Stream::Mux(_) => None,
+        }
+    }
+
+    /// Where the client was connecting to before a `REDIRECT` or `DNAT` rule
+    /// sent it here. Connections a `TPROXY` rule delivers, and ones that were
+    /// not redirected at all, keep their destination as the local address.
+    pub fn original_destination(&self) -> io::Result<SocketAddr> {
+        let stream = match self {
+            Stream::Tcp(stream) => stream,
+            Stream::Unix(_) | Stream::Mux(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Only TCP connections have an original destination",
+                ))
+            }
+        };
+        let local = stream.local_addr()?;
+        match nat_destination(stream, local.is_ipv6()) {
+            Ok(original) => Ok(original),
+            // No NAT entry, or no connection tracking loaded at all
+            Err(err) if matches!(err.raw_os_error(), Some(libc::ENOENT | libc::ENOPROTOOPT)) => {
+                Ok(local)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn into_split(self) -> (ReadHalf, WriteHalf) {
+        match self {
+            Stream::Tcp(stream) => {
+                let (read, write) = stream.into_split();
+                (ReadHalf::Tcp(read), WriteHalf::Tcp(write))
+            }
+            Stream::Unix(stream) => {
+                let (read, write) = stream.into_split();
+                (ReadHalf::Unix(read), WriteHalf::Unix(write))
+            }
+            Stream::Mux(stream) => {
+                let (read, write) = stream.into_split();
+                (ReadHalf::Mux(read), WriteHalf::Mux(write))
+            }
+        }
+    }
+}
+
+pub enum ReadHalf {
+    Tcp(tcp::OwnedReadHalf),
+    Unix(unix::OwnedReadHalf),
+    Mux(DuplexStream),
+}
+
+pub enum WriteHalf {
+    Tcp(tcp::OwnedWriteHalf),
+    Unix(unix::OwnedWriteHalf),
+    Mux(DuplexStream),
+}
+
+impl WriteHalf {
+    /// Closes the connection with a TCP reset instead of a FIN, once the read
+    /// half is dropped too. Unix domain sockets and multiplexed streams have no
+    /// resets and just close.
+    pub fn reset(self) {
+        match self {
+            WriteHalf::Tcp(write) => {
+                let _ = SockRef::from(write.as_ref()).set_linger(Some(Duration::from_secs(0)));
+                write.forget();
+            }
+            WriteHalf::Unix(write) => write.forget(),
+            WriteHalf::Mux(write) => drop(write),
+        }
+    }
+
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match self {
+            WriteHalf::Tcp(write) => write.as_ref().set_nodelay(nodelay),
+            WriteHalf::Unix(_) | WriteHalf::Mux(_) => Ok(()),
+        }
+    }
+}
+
+macro_rules! dispatch {
+    ($self:ident, $inner:ident => $body:expr, $($variant:path),+) => {
+        match $self.get_mut() {
+            $($variant($inner) => $body,)+
+        }
+    };
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        dispatch!(self, s => Pin::new(s).poll_read(cx, buf), Stream::Tcp, Stream::Unix, Stream::Mux)
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        dispatch!(self, s => Pin::new(s).poll_write(cx, buf), Stream::Tcp, Stream::Unix, Stream::Mux)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        dispatch!(self, s => Pin::new(s).poll_flush(cx), Stream::Tcp, Stream::Unix, Stream::Mux)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        dispatch!(self, s => Pin::new(s).poll_shutdown(cx), Stream::Tcp, Stream::Unix, Stream::Mux)
+    }
+}
+
+impl AsyncRead for ReadHalf {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        dispatch!(self, s => Pin::new(s).poll_read(cx, buf), ReadHalf::Tcp, ReadHalf::Unix, ReadHalf::Mux)
+    }
+}
+
+impl AsyncWrite for WriteHalf {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        dispatch!(self, s => Pin::new(s).poll_write(cx, buf), WriteHalf::Tcp, WriteHalf::Unix, WriteHalf::Mux)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        dispatch!(self, s => Pin::new(s).poll_flush(cx), WriteHalf::Tcp, WriteHalf::Unix, WriteHalf::Mux)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        dispatch!(self, s => Pin::new(s).poll_shutdown(cx), WriteHalf::Tcp, WriteHalf::Unix, WriteHalf::Mux)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(host: &str, port: u16) -> Target {
+        Target {
+            host: host.to_owned(),
+            port,
+        }
+    }
+
+    #[test]
+    fn allows_matching_destinations() {
+        let allowed: Allowlist = "*.Example.com:443, 10.0.0.1:*, [::1]:80".parse().unwrap();
+        assert!(allowed.allows(&target("api.example.com", 443)));
+        assert!(allowed.allows(&target("A.B.EXAMPLE.COM", 443)));
+        assert!(!allowed.allows(&target("example.com", 443)));
+        assert!(!allowed.allows(&target("badexample.com", 443)));
+        assert!(!allowed.allows(&target("api.example.com", 80)));
+        assert!(allowed.allows(&target("10.0.0.1", 22)));
+        assert!(allowed.allows(&target("0:0:0:0:0:0:0:1", 80)));
+        assert!("*:*"
+            .parse::<Allowlist>()
+            .unwrap()
+            .allows(&target("anything", 1)));
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        for pattern in &[
+            "*.:443",
+            "*example.com:443",
+            ":443",
+            "example.com",
+            "host:http",
+        ] {
+            assert!(pattern.parse::<Allowlist>().is_err(), "{}", pattern);
+        }
+    }
+}
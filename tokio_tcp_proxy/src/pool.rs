@@ -0,0 +1,159 @@
+use crate::net::{Connector, Stream};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::Notify;
+use tracing::debug;
+
+/// How long to wait before opening another warm connection after one failed
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Upstream connections opened ahead of the clients, so that a client does
+/// not wait for the handshake. Each one goes to a single client, and a
+/// background task opens its replacement.
+pub struct Pool {
+    connector: Arc<Connector>,
+    size: usize,
+    /// How long a connection may sit in the pool before it is replaced, in
+    /// case the upstream drops idle connections without closing them
+    max_idle: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    idle: Mutex<VecDeque<Warm>>,
+    refill: Arc<Notify>,
+    started: AtomicBool,
+}
+
+struct Warm {
+    stream: Stream,
+    opened: Instant,
+}
+
+impl Pool {
+    pub fn new(
+        connector: Arc<Connector>,
+        size: usize,
+        max_idle: Option<Duration>,
+        connect_timeout: Option<Duration>,
+    ) -> Arc<Pool> {
+        Arc::new(Pool {
+            connector,
+            size,
+            max_idle,
+            connect_timeout,
+            idle: Mutex::new(VecDeque::with_capacity(size)),
+            refill: Arc::new(Notify::new()),
+            started: AtomicBool::new(false),
+        })
+    }
+
+    /// Starts filling the pool, unless a previous call already did
+    pub fn start(self: &Arc<Pool>) {
+        if !self.started.swap(true, Ordering::Relaxed) {
+            tokio::spawn(fill(
+                Arc::downgrade(self),
+                Arc::clone(&self.refill),
+                self.max_idle,
+            ));
+        }
+    }
+
+    /// Whether this pool can stand in for `other` after a reload, keeping its
+    /// connections
+    pub fn same_settings(&self, other: &Pool) -> bool {
+        self.size == other.size
+            && self.max_idle == other.max_idle
+            && self.connect_timeout == other.connect_timeout
+    }
+
+    /// The oldest connection that is still open, if there is one
+    pub fn take(&self) -> Option<Stream> {
+        let mut idle = self.idle.lock().unwrap();
+        let mut taken = None;
+        while let Some(warm) = idle.pop_front() {
+            if self.usable(&warm) {
+                taken = Some(warm.stream);
+                break;
+            }
+        }
+        drop(idle);
+        self.refill.notify_one();
+        taken
+    }
+
+    fn usable(&self, warm: &Warm) -> bool {
+        let fresh = self
+            .max_idle
+            .is_none_or(|max_idle| warm.opened.elapsed() < max_idle);
+        fresh && warm.stream.is_open()
+    }
+
+    /// Drops the connections that went stale, and returns how many are left
+    fn prune(&self) -> usize {
+        let mut idle = self.idle.lock().unwrap();
+        idle.retain(|warm| self.usable(warm));
+        idle.len()
+    }
+
+    async fn open(&self) -> Option<Stream> {
+        let connect = self.connector.connect();
+        let connected = match self.connect_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, connect).await.ok(),
+            None => Some(connect.await),
+        };
+        match connected {
+            Some(Ok(stream)) => Some(stream),
+            Some(Err(err)) => {
+                debug!(error = %err, "failed to open a warm upstream connection");
+                None
+            }
+            None => {
+                debug!("timed out opening a warm upstream connection");
+                None
+            }
+        }
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        // Lets the filling task see that the pool is gone
+        self.refill.notify_one();
+    }
+}
+
+/// Keeps the pool full until it is dropped, which happens when a config
+/// reload replaces the route's upstreams. A full pool is checked again when a
+/// connection is taken, and every `max_idle` for ones that went stale.
+async fn fill(weak: Weak<Pool>, refill: Arc<Notify>, max_idle: Option<Duration>) {
+    loop {
+        let pool = match weak.upgrade() {
+            Some(pool) => pool,
+            None => return,
+        };
+        if pool.prune() >= pool.size {
+            drop(pool);
+            match max_idle {
+                Some(max_idle) => {
+                    let _ = tokio::time::timeout(max_idle, refill.notified()).await;
+                }
+                None => refill.notified().await,
+            }
+            continue;
+        }
+        match pool.open().await {
+            Some(stream) => pool.idle.lock().unwrap().push_back(Warm {
+                stream,
+                opened: Instant::now(),
+            }),
+            None => {
+                drop(pool);
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,713 @@
+use crate::{
+    access_log::{CloseReason, Entry, Side},
+    backends::{self, Affinity, Backend, Backends, StickyTable},
+    breaker::Breaker,
+    config::{Destination, Route, Strategy},
+    faults::{Fault, Injector},
+    http_connect::{self, Prefixed},
+    mirror::{Mirror, Mirrored},
+    mux::{self, MuxClient},
+    net::{Address, Connector, Listener, Stream, Target, WriteHalf},
+    pool::Pool,
+    registry::{until_killed, Counted, Registration},
+    socks::{self, Users},
+    tap::Tapped,
+    transform::{Chain, Transform},
+    tunnel::{Role, Session, Tunnel},
+    ACCESS_LOG, REGISTRY, TAP,
+};
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    task::JoinHandle,
+};
+use tracing::{debug, field, info, info_span, warn, Instrument, Span};
+
+/// Everything a connection needs, snapshotted when it is accepted so that a
+/// reload only applies to new connections
+pub struct Settings {
+    pub route: Route,
+    backends: Backends,
+    shadow: Option<Arc<Connector>>,
+    tunnel: Option<Tunnel>,
+    mux: Option<MuxClient>,
+    socks_users: Option<Users>,
+    /// The address the listener is bound to, for routes that connect to the
+    /// original destination
+    listening: Option<SocketAddr>,
+}
+
+impl Settings {
+    /// `previous` is the route's settings before a reload, whose backends
+    /// keep their state
+    pub async fn new(route: Route, previous: Option<&Settings>) -> io::Result<Settings> {
+        let rate = route.breaker.failure_rate;
+        if !(0.0..=1.0).contains(&rate) || (rate > 0.0 && route.breaker.window == 0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "A breaker failure rate is between 0 and 1, over a window of at least one connect",
+            ));
+        }
+        let mut upstreams = Vec::with_capacity(route.upstream.0.len());
+        for upstream in &route.upstream.0 {
+            let connector = Connector::new(
+                &upstream.address,
+                route.resolve_ttl,
+                route.hosts_file.clone(),
+                route.connect_attempt_delay,
+            )
+            .await?;
+            let breaker = Some(route.breaker)
+                .filter(|thresholds| thresholds.enabled())
+                .map(|thresholds| {
+                    let breaker = Arc::new(Breaker::new(upstream.address.to_string(), thresholds));
+                    REGISTRY.watch_breaker(&breaker);
+                    breaker
+                });
+            let connector = Arc::new(connector);
+            // Multiplexing clients share their connections already
+            let warm = Some(route.warm_connections)
+                .filter(|&size| {
+                    size > 0 && route.destination == Destination::Fixed && route.mux.is_none()
+                })
+                .map(|size| {
+                    Pool::new(
+                        Arc::clone(&connector),
+                        size,
+                        route.warm_max_idle,
+                        route.connect_timeout,
+                    )
+                });
+            upstreams.push(Backend::new(
+                upstream.address.clone(),
+                connector,
+                upstream.weight,
+                breaker,
+                warm,
+            ));
+        }
+        let sticky = match route.affinity {
+            Affinity::None => None,
+            affinity => Some(StickyTable::new(
+                affinity,
+                route.sticky_table_size,
+                route.sticky_ttl,
+            )),
+        };
+        let backends = Backends::new(
+            upstreams,
+            route.slow_start,
+            sticky,
+            previous.map(|previous| &previous.backends),
+        );
+        let shadow = match &route.shadow {
+            Some(shadow) => Some(Arc::new(
+                Connector::new(
+                    shadow,
+                    route.resolve_ttl,
+                    route.hosts_file.clone(),
+                    route.connect_attempt_delay,
+                )
+                .await?,
+            )),
+            None => None,
+        };
+        let tunnel = match (route.tunnel, &route.tunnel_key) {
+            (Some(_), _) if route.destination != Destination::Fixed => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "A tunnel needs a fixed upstream",
+                ))
+            }
+            (Some(Role::Server), _) if route.affinity == Affinity::Prefix => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Prefix affinity needs plaintext from clients, not a tunnel",
+                ))
+            }
+            (Some(role), Some(key)) => Some(Tunnel::load(role, key)?),
+            (Some(_), None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "A tunnel needs a key file",
+                ))
+            }
+            (None, _) => None,
+        };
+        let mux = match route.mux {
+            Some(mux::Role::Client) if route.destination != Destination::Fixed => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "A multiplexing client needs a fixed upstream",
+                ))
+            }
+            Some(mux::Role::Client) if backends.len() > 1 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "A multiplexing client needs a single upstream",
+                ))
+            }
+            Some(mux::Role::Client) => Some(MuxClient::new(route.mux_connections)),
+            _ => None,
+        };
+        let socks_users = match &route.socks_users {
+            Some(path) => Some(Users::load(path)?),
+            None => None,
+        };
+        let listening = match (&route.listen, route.destination) {
+            (Address::Tcp(addr), Destination::Original) => {
+                tokio::net::lookup_host(addr).await?.next()
+            }
+            _ => None,
+        };
+        Ok(Settings {
+            route,
+            backends,
+            shadow,
+            tunnel,
+            mux,
+            socks_users,
+            listening,
+        })
+    }
+}
+
+pub type SharedSettings = Arc<RwLock<Arc<Settings>>>;
+
+pub async fn accept(listener: Listener, settings: SharedSettings) {
+    loop {
+        let (socket, peer) = listener
+            .accept()
+            .await
+            .expect("Failed to accept a new connection");
+
+        if REGISTRY.is_draining() {
+            debug!(?peer, "draining, closed new connection");
+            continue;
+        }
+        let settings = Arc::clone(&settings.read().unwrap());
+        // Turned away before anything is set up for them, upstream included
+        if let Some(peer) = peer.filter(|peer| !settings.route.admits(peer.ip())) {
+            let rejected = REGISTRY.reject();
+            warn!(%peer, listen = %settings.route.listen, rejected, "client rejected");
+            continue;
+        }
+        if settings.route.mux == Some(mux::Role::Server) {
+            tokio::spawn(mux::serve(socket, peer, settings));
+        } else {
+            tokio::spawn(handle(socket, peer, settings));
+        }
+    }
+}
+
+pub async fn handle(socket: Stream, peer: Option<SocketAddr>, settings: Arc<Settings>) {
+    let route = &settings.route;
+    // A client that picks its own destination only names it after the
+    // handshake
+    let upstream = match route.destination {
+        Destination::Fixed => route.upstream.to_string(),
+        destination => destination.to_string(),
+    };
+    let registration = REGISTRY.register(
+        route.listen.to_string(),
+        peer.map_or_else(|| "unix".to_owned(), |peer| peer.to_string()),
+        upstream.clone(),
+        route.strategy,
+    );
+    let connection = &registration.connection;
+    let span = info_span!(
+        "connection",
+        id = connection.id,
+        listen = %connection.listen,
+        client = %connection.client,
+        upstream = %upstream,
+        strategy = ?route.strategy,
+    );
+
+    let start = SystemTime::now();
+    let killed = registration.killed.clone();
+    async {
+        debug!("accepted");
+        let reason = until_killed(proxy(socket, peer, &settings, &registration), killed)
+            .await
+            .unwrap_or_else(|| match *connection.injected.lock().unwrap() {
+                Some(fault) => CloseReason::Injected(fault),
+                None => CloseReason::Killed,
+            });
+        let bytes_up = connection.bytes(Side::Client);
+        let bytes_down = connection.bytes(Side::Upstream);
+        info!(bytes_up, bytes_down, reason = %reason, "connection closed");
+        ACCESS_LOG.log(&Entry {
+            start,
+            duration: connection.started.elapsed(),
+            client: &connection.client,
+            upstream: &connection.upstream.lock().unwrap(),
+            bytes_up: bytes_up.unwrap_or(0),
+            bytes_down: bytes_down.unwrap_or(0),
+            close: reason,
+        });
+    }
+    .instrument(span)
+    .await;
+}
+
+/// Connects upstream and forwards until both directions are done. Returns the
+/// reason the first direction stopped.
+async fn proxy(
+    mut socket: Stream,
+    peer: Option<SocketAddr>,
+    settings: &Settings,
+    registration: &Registration<'_>,
+) -> CloseReason {
+    let route = &settings.route;
+    let tunnel = settings.tunnel.as_ref();
+
+    // A tunnel server only connects upstream for clients that hold the key
+    let mut session = None;
+    if let Some(tunnel) = tunnel.filter(|tunnel| tunnel.role() == Role::Server) {
+        match handshake(tunnel, &mut socket, route.connect_timeout).await {
+            Ok(server) => session = Some(server),
+            Err(reason) => return reason,
+        }
+    }
+
+    // What the client sent before the upstream was picked, after a CONNECT
+    // request or as an affinity prefix, which goes upstream first
+    let mut early = Vec::new();
+    let requested = match route.destination {
+        Destination::Fixed => None,
+        Destination::Socks5 => {
+            let users = settings.socks_users.as_ref();
+            let handshake = socks::accept(&mut socket, users);
+            match negotiate(handshake, route.connect_timeout).await {
+                Ok(requested) => Some(requested),
+                Err(reason) => return reason,
+            }
+        }
+        Destination::Connect => {
+            let handshake = http_connect::accept(&mut socket);
+            match negotiate(handshake, route.connect_timeout).await {
+                Ok((requested, sent)) => {
+                    early = sent;
+                    Some(requested)
+                }
+                Err(reason) => return reason,
+            }
+        }
+        Destination::Original => match original_destination(&socket, settings.listening) {
+            Ok(original) => Some(original),
+            Err(reason) => return reason,
+        },
+    };
+    if let Some(requested) = &requested {
+        Span::current().record("upstream", field::display(requested));
+        *registration.connection.upstream.lock().unwrap() = requested.to_string();
+        let allowed = route
+            .allow_destinations
+            .as_ref()
+            .is_none_or(|allowed| allowed.allows(requested));
+        if !allowed {
+            warn!("destination not allowed");
+            let denied = Err(io::ErrorKind::PermissionDenied);
+            let _ = reply(route.destination, &mut socket, denied).await;
+            return CloseReason::NotAllowed;
+        }
+    }
+
+    // A fixed upstream may be one of several backends, which clients with an
+    // affinity key stick to, and which fail fast while their breaker is open
+    let backends = &settings.backends;
+    let mut key = None;
+    let mut backend = 0;
+    if requested.is_none() && backends.len() > 1 {
+        key = match route.affinity {
+            Affinity::None => None,
+            Affinity::ClientIp => peer.map(|peer| backends::client_ip_key(peer.ip())),
+            Affinity::Prefix => {
+                match read_prefix(&mut socket, route.affinity_prefix, route.affinity_timeout).await
+                {
+                    Ok(prefix) => {
+                        early = prefix.clone();
+                        Some(prefix).filter(|prefix| !prefix.is_empty())
+                    }
+                    Err(err) => return CloseReason::ReadError(Side::Client, err.kind()),
+                }
+            }
+        };
+    }
+    if requested.is_none() {
+        backend = match backends.pick(key.as_deref()) {
+            Some(backend) => backend,
+            None => {
+                warn!("circuit breaker open, failing fast");
+                return CloseReason::CircuitOpen;
+            }
+        };
+        if backends.len() > 1 {
+            let address = backends.address(backend);
+            Span::current().record("upstream", field::display(address));
+            *registration.connection.upstream.lock().unwrap() = address.to_string();
+        }
+    }
+
+    // A multiplexing client opens a stream on one of its shared connections
+    let connect = async {
+        match (&requested, &settings.mux) {
+            (Some(requested), _) => {
+                requested
+                    .connect(route.hosts_file.as_deref(), route.connect_attempt_delay)
+                    .await
+            }
+            (None, Some(mux)) => mux.open(backends.connector(backend), peer).await,
+            (None, None) => match backends.take_warm(backend) {
+                Some(warm) => Ok(warm),
+                None => backends.connector(backend).connect().await,
+            },
+        }
+    };
+    // `None` when the connect timed out
+    let connected = match route.connect_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, connect).await.ok(),
+        None => Some(connect.await),
+    };
+    if requested.is_some() {
+        let result = match &connected {
+            Some(Ok(target)) => Ok(target.local_addr()),
+            Some(Err(err)) => Err(err.kind()),
+            None => Err(io::ErrorKind::TimedOut),
+        };
+        if let Err(err) = reply(route.destination, &mut socket, result).await {
+            return CloseReason::WriteError(Side::Client, err.kind());
+        }
+    }
+    let mut target = match connected {
+        Some(Ok(target)) => {
+            if requested.is_none() {
+                backends.connected(backend);
+            }
+            target
+        }
+        Some(Err(err)) => {
+            warn!(error = %err, "failed to connect to upstream");
+            if requested.is_none() {
+                backends.failed(backend, key.as_deref());
+            }
+            return CloseReason::ConnectFailed(err.kind());
+        }
+        None => {
+            warn!("timed out connecting to upstream");
+            if requested.is_none() {
+                backends.failed(backend, key.as_deref());
+            }
+            return CloseReason::ConnectTimeout;
+        }
+    };
+
+    if let Some(tunnel) = tunnel.filter(|tunnel| tunnel.role() == Role::Client) {
+        match handshake(tunnel, &mut target, route.connect_timeout).await {
+            Ok(client) => session = Some(client),
+            Err(reason) => return reason,
+        }
+    }
+
+    // Transforms, tunnels and faults need the custom forwarder, whatever the
+    // strategy
+    let transforms = |from| {
+        let chain = Chain::new(&route.transform, route.transform_mode, from);
+        match &session {
+            Some(session) => Some(session.wrap(chain, from)),
+            None => chain,
+        }
+    };
+    let (transform_up, transform_down) = (transforms(Side::Client), transforms(Side::Upstream));
+
+    let connection = &registration.connection;
+    let mirror = Mirror::start(settings.shadow.as_ref(), route.connect_timeout);
+    let plain = transform_up.is_none() && transform_down.is_none() && !route.faults.is_enabled();
+    if route.strategy == Strategy::TokioCopyBidirectional && plain {
+        let socket = Prefixed::new(early, socket);
+        let socket = Tapped::new(socket, &TAP, connection.id, Side::Client);
+        let socket = Mirrored::new(socket, mirror);
+        let target = Tapped::new(target, &TAP, connection.id, Side::Upstream);
+        let mut socket = Counted::new(socket, connection.counter(Side::Client));
+        let mut target = Counted::new(target, connection.counter(Side::Upstream));
+        return match tokio::io::copy_bidirectional(&mut target, &mut socket).await {
+            Ok(_) => CloseReason::Closed,
+            Err(err) => CloseReason::Error(err.kind()),
+        };
+    }
+
+    let (client_read, client_write) = socket.into_split();
+    let (upstream_read, upstream_write) = target.into_split();
+    // Sealing reframes the stream, and a message only opens once all of it
+    // arrives. Nagle's algorithm on either leg would hold back tails until a
+    // delayed ACK.
+    if session.is_some() {
+        let nodelay = client_write
+            .set_nodelay(true)
+            .and_then(|_| upstream_write.set_nodelay(true));
+        if let Err(err) = nodelay {
+            warn!(error = %err, "failed to set TCP_NODELAY for the tunnel");
+        }
+    }
+    let client_read = Prefixed::new(early, client_read);
+    let client_read = Tapped::new(client_read, &TAP, connection.id, Side::Client);
+    let upstream_read = Tapped::new(upstream_read, &TAP, connection.id, Side::Upstream);
+    let client_read = Mirrored::new(client_read, mirror);
+    let client_read = Counted::new(client_read, connection.counter(Side::Client));
+    let upstream_read = Counted::new(upstream_read, connection.counter(Side::Upstream));
+    let mut upstream_handle = spawn_direction(
+        Side::Client,
+        client_read,
+        upstream_write,
+        transform_up,
+        route,
+        registration,
+    );
+    let mut downstream_handle = spawn_direction(
+        Side::Upstream,
+        upstream_read,
+        client_write,
+        transform_down,
+        route,
+        registration,
+    );
+
+    // Whichever direction stops first explains why the connection closed
+    tokio::select! {
+        up = &mut upstream_handle => {
+            let _ = downstream_handle.await;
+            up.unwrap_or(CloseReason::Killed)
+        }
+        down = &mut downstream_handle => {
+            let _ = upstream_handle.await;
+            down.unwrap_or(CloseReason::Killed)
+        }
+    }
+}
+
+/// Runs the handshake of a client that picks its own destination, within the
+/// connect timeout
+async fn negotiate<F, T>(handshake: F, timeout: Option<Duration>) -> Result<T, CloseReason>
+where
+    F: Future<Output = io::Result<T>>,
+{
+    let result = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, handshake)
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+        None => handshake.await,
+    };
+    result.map_err(|err| {
+        warn!(error = %err, "client handshake failed");
+        CloseReason::HandshakeFailed(err.kind())
+    })
+}
+
+/// Reads up to `len` bytes, fewer if the client stops sending first or
+/// `timeout` runs out
+async fn read_prefix(
+    socket: &mut Stream,
+    len: usize,
+    timeout: Option<Duration>,
+) -> io::Result<Vec<u8>> {
+    let mut prefix = Vec::with_capacity(len);
+    let mut limited = (&mut *socket).take(len as u64);
+    let read = limited.read_to_end(&mut prefix);
+    let read = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, read).await.ok(),
+        None => Some(read.await),
+    };
+    match read {
+        Some(Err(err)) => Err(err),
+        // What arrived before the timeout still picks a backend
+        Some(Ok(_)) | None => Ok(prefix),
+    }
+}
+
+/// Where a redirected client was connecting to. A client that connects to
+/// the listener itself would have the proxy connect to itself, over and over.
+fn original_destination(
+    socket: &Stream,
+    listening: Option<SocketAddr>,
+) -> Result<Target, CloseReason> {
+    let original = socket.original_destination().map_err(|err| {
+        warn!(error = %err, "failed to get the original destination");
+        CloseReason::HandshakeFailed(err.kind())
+    })?;
+    let loops = listening.is_some_and(|listening| {
+        let ip = if listening.ip().is_unspecified() {
+            socket.local_addr().map(|local| local.ip())
+        } else {
+            Some(listening.ip())
+        };
+        original.port() == listening.port() && Some(original.ip()) == ip
+    });
+    if loops {
+        warn!(%original, "original destination is the proxy itself");
+        return Err(CloseReason::Loop);
+    }
+    Ok(Target {
+        host: original.ip().to_string(),
+        port: original.port(),
+    })
+}
+
+/// Tells a client that picked its own destination how connecting to it went
+async fn reply(
+    destination: Destination,
+    socket: &mut Stream,
+    connected: Result<Option<SocketAddr>, io::ErrorKind>,
+) -> io::Result<()> {
+    match destination {
+        Destination::Fixed | Destination::Original => Ok(()),
+        Destination::Socks5 => socks::reply(socket, connected).await,
+        Destination::Connect => http_connect::reply(socket, connected).await,
+    }
+}
+
+/// Runs the tunnel handshake, within the connect timeout
+async fn handshake(
+    tunnel: &Tunnel,
+    stream: &mut Stream,
+    timeout: Option<Duration>,
+) -> Result<Session, CloseReason> {
+    let handshake = tunnel.handshake(stream);
+    let result = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, handshake)
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+        None => handshake.await,
+    };
+    result.map_err(|err| {
+        warn!(error = %err, "tunnel handshake failed");
+        CloseReason::HandshakeFailed(err.kind())
+    })
+}
+
+/// Forwards the bytes read `from` one side on their own task. The task watches
+/// for a kill itself, since it outlives the connection future when that is
+/// dropped.
+fn spawn_direction<R>(
+    from: Side,
+    read: R,
+    write: WriteHalf,
+    transform: Option<Chain>,
+    route: &Route,
+    registration: &Registration<'_>,
+) -> JoinHandle<CloseReason>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let (strategy, buf_size, idle_timeout) = (route.strategy, route.buf_size, route.idle_timeout);
+    let connection = Arc::clone(&registration.connection);
+    let faults = Injector::new(&route.faults, connection.id, from);
+    let killed = registration.killed.clone();
+    let span = info_span!("direction", direction = from.direction());
+    tokio::spawn(
+        async move {
+            // The write half outlives the copy, so a reset can still skip its FIN
+            let (mut read, mut write) = (read, write);
+            let copy = async {
+                if strategy == Strategy::TokioCopy && transform.is_none() && faults.is_none() {
+                    match tokio::io::copy(&mut read, &mut write).await {
+                        Ok(_) => CloseReason::Eof(from),
+                        Err(err) => CloseReason::Error(err.kind()),
+                    }
+                } else {
+                    forward_custom(
+                        &mut read,
+                        &mut write,
+                        from,
+                        buf_size,
+                        idle_timeout,
+                        faults,
+                        transform,
+                    )
+                    .await
+                }
+            };
+            let reason = until_killed(copy, killed)
+                .await
+                .unwrap_or(CloseReason::Killed);
+            // An injected fault ends the other direction as well
+            if let CloseReason::Injected(fault) = reason {
+                connection.injected.lock().unwrap().get_or_insert(fault);
+                connection.kill();
+            }
+            if *connection.injected.lock().unwrap() == Some(Fault::Reset) {
+                write.reset();
+            }
+            debug!(bytes = connection.bytes(from), reason = %reason, "direction closed");
+            reason
+        }
+        .instrument(span),
+    )
+}
+
+/// Copies the bytes read `from` one side to the other, until EOF or an error,
+/// or until no data arrives for `idle_timeout`. With transforms, every chunk
+/// goes through them first, and with an injector, on the way out.
+async fn forward_custom<R>(
+    mut read: R,
+    write: &mut WriteHalf,
+    from: Side,
+    buf_size: usize,
+    idle_timeout: Option<Duration>,
+    mut faults: Option<Injector>,
+    mut transform: Option<Chain>,
+) -> CloseReason
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buf: Vec<u8> = vec![0; buf_size];
+    let mut transformed: Vec<u8> = Vec::new();
+    loop {
+        let n = match idle_timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, read.read(&mut buf)).await {
+                Ok(read) => read,
+                Err(_) => return CloseReason::IdleTimeout,
+            },
+            None => read.read(&mut buf).await,
+        };
+        let n = match n {
+            Ok(n) => n,
+            Err(err) => return CloseReason::ReadError(from, err.kind()),
+        };
+        let chunk = match &mut transform {
+            Some(transform) => {
+                transformed.clear();
+                let applied = if n == 0 {
+                    transform.finish(&mut transformed)
+                } else {
+                    transform.apply(&buf[..n], &mut transformed)
+                };
+                if let Err(err) = applied {
+                    return CloseReason::TransformError(from, err.kind());
+                }
+                &mut transformed[..]
+            }
+            None => &mut buf[..n],
+        };
+        if !chunk.is_empty() {
+            let written = match &mut faults {
+                Some(faults) => faults.write(write, chunk).await,
+                None => write.write_all(chunk).await.map(|_| None),
+            };
+            match written {
+                Ok(None) => {}
+                Ok(Some(fault)) => return CloseReason::Injected(fault),
+                Err(err) => return CloseReason::WriteError(from.other(), err.kind()),
+            }
+        }
+        if n == 0 {
+            return CloseReason::Eof(from);
+        }
+    }
+}
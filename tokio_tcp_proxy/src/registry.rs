@@ -0,0 +1,315 @@
+use crate::{access_log::Side, breaker::Breaker, config::Strategy, faults::Fault};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    task::{Context, Poll},
+    time::Instant,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::watch,
+};
+
+/// Every open proxied connection, for the admin API. Without it, connections
+/// stay out of the list, and their bytes are only counted for the access log.
+#[derive(Default)]
+pub struct Registry {
+    /// Whether connections are listed
+    listing: bool,
+    /// Whether connections count their bytes
+    counting: bool,
+    next_id: AtomicU64,
+    draining: AtomicBool,
+    /// Clients turned away by the allow and deny lists
+    rejected: AtomicU64,
+    connections: Mutex<HashMap<u64, Arc<Connection>>>,
+    /// The circuit breakers of the routes' upstreams, for as long as a route
+    /// uses them
+    breakers: Mutex<Vec<Weak<Breaker>>>,
+}
+
+pub struct Connection {
+    pub id: u64,
+    pub listen: String,
+    pub client: String,
+    /// Where the connection goes, once a client that picks its own
+    /// destination has said so
+    pub upstream: Mutex<String>,
+    pub strategy: Strategy,
+    pub started: Instant,
+    bytes_up: Arc<AtomicU64>,
+    bytes_down: Arc<AtomicU64>,
+    counted: bool,
+    /// The fault injected into the connection, which also tells both
+    /// directions to close with a TCP reset rather than a FIN
+    pub injected: Mutex<Option<Fault>>,
+    kill: watch::Sender<bool>,
+}
+
+/// A connection as listed by the admin API
+#[derive(Serialize)]
+pub struct ConnectionSummary {
+    pub id: u64,
+    pub listen: String,
+    pub client: String,
+    pub upstream: String,
+    pub strategy: Strategy,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub age_secs: f64,
+}
+
+/// Counters for the admin API
+#[derive(Serialize)]
+pub struct Stats {
+    pub open_connections: usize,
+    pub rejected_clients: u64,
+    pub breakers: Vec<BreakerSummary>,
+}
+
+/// An upstream's circuit breaker as listed by the admin API
+#[derive(Serialize)]
+pub struct BreakerSummary {
+    pub upstream: String,
+    /// `closed`, `open` or `half-open`
+    pub state: String,
+    /// Clients turned away while the breaker was open
+    pub failed_fast: u64,
+}
+
+/// Removes the connection from the registry when the proxying task ends
+pub struct Registration<'a> {
+    registry: &'a Registry,
+    pub connection: Arc<Connection>,
+    pub killed: watch::Receiver<bool>,
+}
+
+impl Registry {
+    /// `listing` is for the admin API, which also needs the byte counts, and
+    /// `counting` is for when only the access log does
+    pub fn new(listing: bool, counting: bool) -> Registry {
+        Registry {
+            listing,
+            counting: listing || counting,
+            ..Registry::default()
+        }
+    }
+
+    pub fn register(
+        &self,
+        listen: String,
+        client: String,
+        upstream: String,
+        strategy: Strategy,
+    ) -> Registration<'_> {
+        let (kill, killed) = watch::channel(false);
+        let connection = Arc::new(Connection {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+            listen,
+            client,
+            upstream: Mutex::new(upstream),
+            strategy,
+            started: Instant::now(),
+            bytes_up: Arc::new(AtomicU64::new(0)),
+            bytes_down: Arc::new(AtomicU64::new(0)),
+            counted: self.counting,
+            injected: Mutex::new(None),
+            kill,
+        });
+        if self.listing {
+            self.connections
+                .lock()
+                .unwrap()
+                .insert(connection.id, Arc::clone(&connection));
+        }
+        Registration {
+            registry: self,
+            connection,
+            killed,
+        }
+    }
+
+    pub fn list(&self) -> Vec<ConnectionSummary> {
+        let mut connections: Vec<ConnectionSummary> = self
+            .connections
+            .lock()
+            .unwrap()
+            .values()
+            .map(|c| ConnectionSummary {
+                id: c.id,
+                listen: c.listen.clone(),
+                client: c.client.clone(),
+                upstream: c.upstream.lock().unwrap().clone(),
+                strategy: c.strategy,
+                bytes_up: c.bytes_up.load(Ordering::Relaxed),
+                bytes_down: c.bytes_down.load(Ordering::Relaxed),
+                age_secs: c.started.elapsed().as_secs_f64(),
+            })
+            .collect();
+        connections.sort_by_key(|c| c.id);
+        connections
+    }
+
+    /// Closes both directions of a connection. Returns false for unknown ids.
+    pub fn kill(&self, id: u64) -> bool {
+        match self.connections.lock().unwrap().get(&id) {
+            Some(connection) => {
+                connection.kill();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// While draining, listeners close new connections right away and open
+    /// ones run to completion
+    pub fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::Relaxed);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Counts a rejected client, and returns how many there have been
+    pub fn reject(&self) -> u64 {
+        self.rejected.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Lists the breaker in the stats until it is dropped
+    pub fn watch_breaker(&self, breaker: &Arc<Breaker>) {
+        let mut breakers = self.breakers.lock().unwrap();
+        breakers.retain(|breaker| breaker.strong_count() > 0);
+        breakers.push(Arc::downgrade(breaker));
+    }
+
+    pub fn stats(&self) -> Stats {
+        let breakers = self
+            .breakers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|breaker| BreakerSummary {
+                upstream: breaker.upstream().to_string(),
+                state: breaker.state().to_string(),
+                failed_fast: breaker.failed_fast(),
+            })
+            .collect();
+        Stats {
+            open_connections: self.connections.lock().unwrap().len(),
+            rejected_clients: self.rejected.load(Ordering::Relaxed),
+            breakers,
+        }
+    }
+}
+
+impl Connection {
+    /// What counts the bytes read from `from`, when they are counted
+    pub fn counter(&self, from: Side) -> Option<Arc<AtomicU64>> {
+        let counter = match from {
+            Side::Client => &self.bytes_up,
+            Side::Upstream => &self.bytes_down,
+        };
+        Some(Arc::clone(counter)).filter(|_| self.counted)
+    }
+
+    /// The bytes read from `from` so far, when they are counted
+    pub fn bytes(&self, from: Side) -> Option<u64> {
+        self.counter(from)
+            .map(|counter| counter.load(Ordering::Relaxed))
+    }
+
+    /// Closes both directions
+    pub fn kill(&self) {
+        let _ = self.kill.send(true);
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        if self.registry.listing {
+            self.registry
+                .connections
+                .lock()
+                .unwrap()
+                .remove(&self.connection.id);
+        }
+    }
+}
+
+/// Runs `future` unless the connection is killed through the admin API first,
+/// in which case it returns `None`
+pub async fn until_killed<F: Future>(
+    future: F,
+    mut killed: watch::Receiver<bool>,
+) -> Option<F::Output> {
+    let killed = async move {
+        while !*killed.borrow() {
+            if killed.changed().await.is_err() {
+                return std::future::pending().await;
+            }
+        }
+    };
+    tokio::select! {
+        output = future => Some(output),
+        _ = killed => None,
+    }
+}
+
+/// Counts the bytes read through a stream, if there is a count, leaving
+/// writes untouched
+pub struct Counted<S> {
+    inner: S,
+    count: Option<Arc<AtomicU64>>,
+}
+
+impl<S> Counted<S> {
+    pub fn new(inner: S, count: Option<Arc<AtomicU64>>) -> Counted<S> {
+        Counted { inner, count }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Some(count) = &this.count {
+            let read = buf.filled().len() - before;
+            if read > 0 {
+                count.fetch_add(read as u64, Ordering::Relaxed);
+            }
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
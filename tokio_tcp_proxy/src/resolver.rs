@@ -0,0 +1,250 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock, Weak},
+    time::Duration,
+};
+use tokio::{net::TcpStream, sync::mpsc, task::JoinHandle};
+use tracing::warn;
+
+/// Resolves a `host:port` upstream and caches the result, so the hot path
+/// never waits on a lookup. With a non-zero TTL, a background task refreshes
+/// the cached addresses; with a zero TTL, every connection resolves again.
+pub struct Resolver {
+    host: String,
+    port: u16,
+    ttl: Duration,
+    hosts_file: Option<PathBuf>,
+    cached: RwLock<Arc<Vec<SocketAddr>>>,
+}
+
+impl Resolver {
+    pub async fn new(
+        upstream: &str,
+        ttl: Duration,
+        hosts_file: Option<PathBuf>,
+    ) -> io::Result<Arc<Resolver>> {
+        let (host, port) = split_host_port(upstream)?;
+        let resolver = Arc::new(Resolver {
+            host,
+            port,
+            ttl,
+            hosts_file,
+            cached: RwLock::new(Arc::new(Vec::new())),
+        });
+
+        let addrs = resolver.lookup().await?;
+        *resolver.cached.write().unwrap() = Arc::new(addrs);
+
+        // Literal IPs never change, so there is nothing to refresh
+        if !ttl.is_zero() && resolver.host.parse::<IpAddr>().is_err() {
+            tokio::spawn(refresh(Arc::downgrade(&resolver), ttl));
+        }
+
+        Ok(resolver)
+    }
+
+    /// Returns the upstream addresses, resolving now only if caching is off
+    pub async fn resolve(&self) -> io::Result<Arc<Vec<SocketAddr>>> {
+        if self.ttl.is_zero() {
+            return self.lookup().await.map(Arc::new);
+        }
+        Ok(Arc::clone(&self.cached.read().unwrap()))
+    }
+
+    async fn lookup(&self) -> io::Result<Vec<SocketAddr>> {
+        lookup(&self.host, self.port, self.hosts_file.as_deref()).await
+    }
+}
+
+/// Looks the host up in the hosts file first, then in the system resolver
+pub async fn lookup(
+    host: &str,
+    port: u16,
+    hosts_file: Option<&Path>,
+) -> io::Result<Vec<SocketAddr>> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+    if let Some(path) = hosts_file {
+        let overrides = read_hosts_file(path).await?;
+        if let Some(ips) = overrides.get(&host.to_ascii_lowercase()) {
+            return Ok(ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect());
+        }
+    }
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port)).await?.collect();
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No addresses found for {}", host),
+        ));
+    }
+    Ok(addrs)
+}
+
+/// Refreshes the cached addresses until the resolver is dropped, which
+/// happens when a config reload replaces the upstream
+async fn refresh(resolver: Weak<Resolver>, ttl: Duration) {
+    loop {
+        tokio::time::sleep(ttl).await;
+        let resolver = match resolver.upgrade() {
+            Some(resolver) => resolver,
+            None => return,
+        };
+        match resolver.lookup().await {
+            Ok(addrs) => *resolver.cached.write().unwrap() = Arc::new(addrs),
+            // Keep serving the last known addresses until a lookup succeeds
+            Err(err) => {
+                warn!(host = %resolver.host, error = %err, "failed to refresh upstream addresses")
+            }
+        }
+    }
+}
+
+pub fn split_host_port(upstream: &str) -> io::Result<(String, u16)> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Expected host:port, got {}", upstream),
+        )
+    };
+    let (host, port) = upstream.rsplit_once(':').ok_or_else(invalid)?;
+    let port = port.parse().map_err(|_| invalid())?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    Ok((host.to_owned(), port))
+}
+
+async fn read_hosts_file(path: &Path) -> io::Result<HashMap<String, Vec<IpAddr>>> {
+    let contents = tokio::fs::read_to_string(path).await?;
+    Ok(parse_hosts(&contents))
+}
+
+/// Parses `/etc/hosts`-style lines: an IP followed by one or more names
+fn parse_hosts(contents: &str) -> HashMap<String, Vec<IpAddr>> {
+    let mut hosts: HashMap<String, Vec<IpAddr>> = HashMap::new();
+    for line in contents.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let ip = match fields.next().map(str::parse::<IpAddr>) {
+            Some(Ok(ip)) => ip,
+            _ => continue,
+        };
+        for name in fields {
+            hosts.entry(name.to_ascii_lowercase()).or_default().push(ip);
+        }
+    }
+    hosts
+}
+
+/// Connects to the first address that answers, happy-eyeballs style: the
+/// address families are interleaved, and a new attempt starts whenever the
+/// previous one fails or has not finished within `attempt_delay`.
+pub async fn connect_any(addrs: &[SocketAddr], attempt_delay: Duration) -> io::Result<TcpStream> {
+    // Nothing to race against
+    if let [addr] = addrs {
+        return TcpStream::connect(addr).await;
+    }
+    let addrs = interleave_families(addrs);
+    let (tx, mut rx) = mpsc::channel(addrs.len().max(1));
+    let mut attempts: Vec<JoinHandle<()>> = Vec::with_capacity(addrs.len());
+    let mut pending = addrs.into_iter();
+    let mut in_flight = 0;
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "No upstream addresses");
+
+    let result = loop {
+        if let Some(addr) = pending.next() {
+            let tx = tx.clone();
+            attempts.push(tokio::spawn(async move {
+                let _ = tx.send(TcpStream::connect(addr).await).await;
+            }));
+            in_flight += 1;
+        } else if in_flight == 0 {
+            break Err(last_err);
+        }
+
+        let has_next = pending.len() > 0;
+        tokio::select! {
+            Some(result) = rx.recv() => {
+                in_flight -= 1;
+                match result {
+                    Ok(stream) => break Ok(stream),
+                    Err(err) => last_err = err,
+                }
+            }
+            _ = tokio::time::sleep(attempt_delay), if has_next => {}
+        }
+    };
+
+    for attempt in attempts {
+        attempt.abort();
+    }
+    result
+}
+
+/// Alternates between the address families, starting with the first one
+fn interleave_families(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let first_is_v6 = addrs.first().is_some_and(SocketAddr::is_ipv6);
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) = addrs
+        .iter()
+        .copied()
+        .partition(|addr| addr.is_ipv6() == first_is_v6);
+    preferred.reverse();
+    other.reverse();
+    let mut ordered = Vec::with_capacity(addrs.len());
+    while let Some(addr) = preferred.pop() {
+        ordered.push(addr);
+        if let Some(addr) = other.pop() {
+            ordered.push(addr);
+        }
+    }
+    ordered.extend(other.into_iter().rev());
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ips(ips: &[&str]) -> Vec<IpAddr> {
+        ips.iter().map(|ip| ip.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn parses_hosts_file() {
+        let hosts = parse_hosts(
+            "# upstreams\n\
+             10.0.0.1  backend  Backend.Example\n\
+             \t10.0.0.2\tbackend # the second one\n\
+             fd00::1 backend6\n\
+             \n\
+             not-an-ip ignored\n\
+             10.0.0.3\n\
+             #10.0.0.4 commented\n",
+        );
+        assert_eq!(hosts["backend"], ips(&["10.0.0.1", "10.0.0.2"]));
+        assert_eq!(hosts["backend.example"], ips(&["10.0.0.1"]));
+        assert_eq!(hosts["backend6"], ips(&["fd00::1"]));
+        assert_eq!(hosts.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn looks_up_hosts_file_first() {
+        let path = std::env::temp_dir().join(format!("resolver-test-{}", std::process::id()));
+        std::fs::write(&path, "192.0.2.7 localhost\n").unwrap();
+        let addrs = lookup("LOCALHOST", 80, Some(&path)).await;
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(addrs.unwrap(), vec!["192.0.2.7:80".parse().unwrap()]);
+    }
+
+    #[test]
+    fn interleaves_families() {
+        let addrs: Vec<SocketAddr> = ["[::1]:1", "[::2]:1", "[::3]:1", "10.0.0.1:1"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let ordered = interleave_families(&addrs);
+        assert_eq!(ordered, vec![addrs[0], addrs[3], addrs[1], addrs[2]]);
+    }
+}
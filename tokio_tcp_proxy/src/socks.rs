@@ -0,0 +1,196 @@
+use crate::net::{Stream, Target};
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const VERSION: u8 = 5;
+
+/// Authentication methods
+const NO_AUTH: u8 = 0x00;
+const PASSWORD: u8 = 0x02;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+
+/// The version of the username/password subnegotiation, from RFC 1929
+const PASSWORD_VERSION: u8 = 1;
+
+const CONNECT: u8 = 1;
+
+/// Address types
+const IPV4: u8 = 1;
+const DOMAIN: u8 = 3;
+const IPV6: u8 = 4;
+
+/// Reply codes
+const SUCCEEDED: u8 = 0;
+const GENERAL_FAILURE: u8 = 1;
+const NOT_ALLOWED: u8 = 2;
+const NETWORK_UNREACHABLE: u8 = 3;
+const HOST_UNREACHABLE: u8 = 4;
+const CONNECTION_REFUSED: u8 = 5;
+const COMMAND_NOT_SUPPORTED: u8 = 7;
+const ADDRESS_TYPE_NOT_SUPPORTED: u8 = 8;
+
+/// The usernames and passwords clients may authenticate with
+pub struct Users(HashMap<String, String>);
+
+impl Users {
+    /// Reads a file of `username:password` lines. Blank lines and lines
+    /// starting with `#` are skipped.
+    pub fn load(path: &Path) -> io::Result<Users> {
+        let contents = std::fs::read_to_string(path)?;
+        let mut users = HashMap::new();
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once(':') {
+                Some((user, password)) => users.insert(user.to_owned(), password.to_owned()),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Expected username:password in {}", path.display()),
+                    ))
+                }
+            };
+        }
+        Ok(Users(users))
+    }
+
+    fn check(&self, user: &[u8], password: &[u8]) -> bool {
+        let user = String::from_utf8_lossy(user);
+        self.0.get(user.as_ref()).map(String::as_bytes) == Some(password)
+    }
+}
+
+/// Negotiates with a SOCKS5 client up to its CONNECT request, and returns
+/// where it asked to go. Without `users`, clients need no authentication.
+/// Requests the proxy cannot serve get their error reply before this fails.
+pub async fn accept(stream: &mut Stream, users: Option<&Users>) -> io::Result<Target> {
+    let [version, count] = read_array(stream).await?;
+    if version != VERSION {
+        return Err(invalid(format!("Unsupported SOCKS version {}", version)));
+    }
+    let mut methods = vec![0; count as usize];
+    stream.read_exact(&mut methods).await?;
+    let method = if users.is_some() { PASSWORD } else { NO_AUTH };
+    if !methods.contains(&method) {
+        stream.write_all(&[VERSION, NO_ACCEPTABLE_METHOD]).await?;
+        return Err(denied("No acceptable SOCKS authentication method"));
+    }
+    stream.write_all(&[VERSION, method]).await?;
+
+    if let Some(users) = users {
+        let [version, len] = read_array(stream).await?;
+        if version != PASSWORD_VERSION {
+            return Err(invalid(format!(
+                "Unsupported SOCKS password version {}",
+                version
+            )));
+        }
+        let mut user = vec![0; len as usize];
+        stream.read_exact(&mut user).await?;
+        let mut password = vec![0; stream.read_u8().await? as usize];
+        stream.read_exact(&mut password).await?;
+        let accepted = users.check(&user, &password);
+        stream
+            .write_all(&[PASSWORD_VERSION, if accepted { 0 } else { 1 }])
+            .await?;
+        if !accepted {
+            return Err(denied("Wrong SOCKS username or password"));
+        }
+    }
+
+    let [version, command, _reserved, address_type] = read_array(stream).await?;
+    if version != VERSION {
+        return Err(invalid(format!("Unsupported SOCKS version {}", version)));
+    }
+    let host = match address_type {
+        IPV4 => Ipv4Addr::from(read_array::<4>(stream).await?).to_string(),
+        IPV6 => Ipv6Addr::from(read_array::<16>(stream).await?).to_string(),
+        DOMAIN => {
+            let mut domain = vec![0; stream.read_u8().await? as usize];
+            stream.read_exact(&mut domain).await?;
+            String::from_utf8(domain).map_err(|_| invalid("Invalid SOCKS domain name"))?
+        }
+        _ => {
+            write_reply(stream, ADDRESS_TYPE_NOT_SUPPORTED, None).await?;
+            return Err(unsupported(format!(
+                "Unsupported SOCKS address type {}",
+                address_type
+            )));
+        }
+    };
+    let port = stream.read_u16().await?;
+    if command != CONNECT {
+        write_reply(stream, COMMAND_NOT_SUPPORTED, None).await?;
+        return Err(unsupported(format!(
+            "Unsupported SOCKS command {}",
+            command
+        )));
+    }
+    Ok(Target { host, port })
+}
+
+/// Tells the client whether connecting to its target worked: the local
+/// address of the upstream connection on success, or the reason it failed
+pub async fn reply(
+    stream: &mut Stream,
+    connected: Result<Option<SocketAddr>, io::ErrorKind>,
+) -> io::Result<()> {
+    match connected {
+        Ok(bound) => write_reply(stream, SUCCEEDED, bound).await,
+        Err(kind) => {
+            let code = match kind {
+                io::ErrorKind::PermissionDenied => NOT_ALLOWED,
+                io::ErrorKind::NetworkUnreachable => NETWORK_UNREACHABLE,
+                io::ErrorKind::HostUnreachable
+                | io::ErrorKind::NotFound
+                | io::ErrorKind::TimedOut => HOST_UNREACHABLE,
+                io::ErrorKind::ConnectionRefused => CONNECTION_REFUSED,
+                _ => GENERAL_FAILURE,
+            };
+            write_reply(stream, code, None).await
+        }
+    }
+}
+
+/// Replies with the bound address, or all zeros when there is none
+async fn write_reply(stream: &mut Stream, code: u8, bound: Option<SocketAddr>) -> io::Result<()> {
+    let bound = bound.unwrap_or_else(|| SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0));
+    let mut reply = vec![VERSION, code, 0];
+    match bound.ip() {
+        IpAddr::V4(ip) => {
+            reply.push(IPV4);
+            reply.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            reply.push(IPV6);
+            reply.extend_from_slice(&ip.octets());
+        }
+    }
+    reply.extend_from_slice(&bound.port().to_be_bytes());
+    stream.write_all(&reply).await
+}
+
+async fn read_array<const N: usize>(stream: &mut Stream) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    stream.read_exact(&mut bytes).await?;
+    Ok(bytes)
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn denied(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, message.into())
+}
+
+fn unsupported(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, message.into())
+}
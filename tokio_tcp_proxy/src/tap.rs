@@ -0,0 +1,178 @@
+use crate::access_log::Side;
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, TrySendError},
+    },
+    task::{Context, Poll},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::warn;
+
+/// Starts every capture file, followed by the format version
+const MAGIC: &[u8; 8] = b"PROXYTAP";
+const VERSION: u16 = 1;
+/// Records waiting for the writing thread before new ones are dropped
+const QUEUE: usize = 4096;
+
+struct Record {
+    micros: u64,
+    id: u64,
+    from: Side,
+    data: Vec<u8>,
+}
+
+/// Writes every chunk read from either side to a capture file. Both Rust TCP
+/// proxies write the same format, which `tap_replay` reads back: the magic
+/// and a big-endian u16 version, then one record per chunk of
+///
+/// - u64 microseconds since the Unix epoch
+/// - u64 connection id
+/// - u8 direction, 0 for client to upstream and 1 for upstream to client
+/// - u32 length, followed by the bytes
+///
+/// A dedicated thread does the writing, so the forwarders only pay for a copy
+/// of each chunk. Records are handed over with `try_send`, so a disk that
+/// cannot keep up loses records rather than holding up the connections.
+pub struct Tap {
+    records: Option<mpsc::SyncSender<Record>>,
+    dropped: AtomicU64,
+    /// Whether the last record was dropped, so only the first of a run warns
+    lagging: AtomicBool,
+}
+
+impl Tap {
+    /// Without a path, the tap records nothing
+    pub fn open(path: Option<&Path>) -> io::Result<Tap> {
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(Tap::new(None)),
+        };
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(MAGIC)?;
+        out.write_all(&VERSION.to_be_bytes())?;
+        out.flush()?;
+
+        let (records, received) = mpsc::sync_channel(QUEUE);
+        std::thread::spawn(move || write_records(out, received));
+        Ok(Tap::new(Some(records)))
+    }
+
+    fn new(records: Option<mpsc::SyncSender<Record>>) -> Tap {
+        Tap {
+            records,
+            dropped: AtomicU64::new(0),
+            lagging: AtomicBool::new(false),
+        }
+    }
+
+    pub fn record(&self, id: u64, from: Side, data: &[u8]) {
+        if let Some(records) = &self.records {
+            let micros = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_micros() as u64);
+            let record = Record {
+                micros,
+                id,
+                from,
+                data: data.to_vec(),
+            };
+            match records.try_send(record) {
+                Ok(()) => self.lagging.store(false, Ordering::Relaxed),
+                Err(TrySendError::Full(_)) => {
+                    let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                    if !self.lagging.swap(true, Ordering::Relaxed) {
+                        warn!(dropped, "tap fell behind, dropping records");
+                    }
+                }
+                // The writing thread failed, and said so already
+                Err(TrySendError::Disconnected(_)) => {}
+            }
+        }
+    }
+}
+
+/// Flushes whenever the queue runs dry, so the file stays current while idle
+fn write_records(mut out: BufWriter<File>, received: mpsc::Receiver<Record>) {
+    while let Ok(record) = received.recv() {
+        let mut result = write_record(&mut out, &record);
+        while let (Ok(()), Ok(record)) = (&result, received.try_recv()) {
+            result = write_record(&mut out, &record);
+        }
+        if let Err(err) = result.and_then(|_| out.flush()) {
+            warn!(error = %err, "failed to write to tap file, no longer tapping");
+            return;
+        }
+    }
+}
+
+fn write_record(out: &mut impl Write, record: &Record) -> io::Result<()> {
+    let direction: u8 = match record.from {
+        Side::Client => 0,
+        Side::Upstream => 1,
+    };
+    out.write_all(&record.micros.to_be_bytes())?;
+    out.write_all(&record.id.to_be_bytes())?;
+    out.write_all(&[direction])?;
+    out.write_all(&(record.data.len() as u32).to_be_bytes())?;
+    out.write_all(&record.data)
+}
+
+/// Records the bytes read through a stream on `tap`, leaving writes untouched
+pub struct Tapped<S> {
+    inner: S,
+    tap: &'static Tap,
+    id: u64,
+    from: Side,
+}
+
+impl<S> Tapped<S> {
+    pub fn new(inner: S, tap: &'static Tap, id: u64, from: Side) -> Tapped<S> {
+        Tapped {
+            inner,
+            tap,
+            id,
+            from,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tapped<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        let read = &buf.filled()[before..];
+        if !read.is_empty() {
+            this.tap.record(this.id, this.from, read);
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tapped<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,342 @@
+use crate::{
+    access_log::Side,
+    transform::{Chain, Transform},
+};
+use serde::Deserialize;
+use snow::{Builder, HandshakeState, StatelessTransportState};
+use std::{io, path::Path, str::FromStr, sync::Arc};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Both ends prove they hold the pre-shared key, and fresh ephemeral keys give
+/// every connection its own session keys
+const PATTERN: &str = "Noise_NNpsk0_25519_ChaChaPoly_BLAKE2s";
+
+/// The largest Noise message, each of which is sent after its big-endian u16
+/// length
+const MAX_MESSAGE: usize = 65535;
+const TAG_LEN: usize = 16;
+
+/// Which end of the encrypted hop this proxy is
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Takes plaintext from its clients and encrypts towards its upstream
+    Client,
+    /// Takes encrypted connections from a tunnel client and forwards plaintext
+    /// to its upstream
+    Server,
+}
+
+impl Role {
+    /// The side of a connection that is encrypted
+    fn sealed(self) -> Side {
+        match self {
+            Role::Client => Side::Upstream,
+            Role::Server => Side::Client,
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Role, String> {
+        match s {
+            "client" => Ok(Role::Client),
+            "server" => Ok(Role::Server),
+            _ => Err(format!("Unknown tunnel role {}", s)),
+        }
+    }
+}
+
+/// A role and the pre-shared key
+pub struct Tunnel {
+    role: Role,
+    key: [u8; 32],
+}
+
+impl Tunnel {
+    /// Reads the key from a file holding 64 hex digits, such as the output of
+    /// `head -c 32 /dev/urandom | xxd -p -c 32`
+    pub fn load(role: Role, path: &Path) -> io::Result<Tunnel> {
+        let hex = std::fs::read_to_string(path)?;
+        let hex = hex.trim();
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} does not hold 64 hex digits", path.display()),
+            )
+        };
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let mut key = [0; 32];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(Tunnel { role, key })
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Runs the handshake on the encrypted leg: the upstream for a client, the
+    /// accepted connection for a server
+    pub async fn handshake<S>(&self, stream: &mut S) -> io::Result<Session>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let builder =
+            Builder::new(PATTERN.parse().expect("Invalid Noise pattern")).psk(0, &self.key);
+        let mut noise = match self.role {
+            Role::Client => builder.build_initiator(),
+            Role::Server => builder.build_responder(),
+        }
+        .map_err(noise_error)?;
+
+        let mut message = vec![0; MAX_MESSAGE];
+        let mut payload = vec![0; MAX_MESSAGE];
+        let mut send = self.role == Role::Client;
+        while !noise.is_handshake_finished() {
+            if send {
+                let len = noise
+                    .write_message(&[], &mut message)
+                    .map_err(noise_error)?;
+                write_frame(stream, &message[..len]).await?;
+            } else {
+                let len = read_frame(stream, &mut message).await?;
+                noise
+                    .read_message(&message[..len], &mut payload)
+                    .map_err(noise_error)?;
+            }
+            send = !send;
+        }
+        Session::new(self.role, noise)
+    }
+}
+
+/// One write per frame, so Nagle's algorithm never holds back the message
+/// behind its length
+async fn write_frame<S: AsyncWrite + Unpin>(stream: &mut S, message: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(2 + message.len());
+    frame.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    frame.extend_from_slice(message);
+    stream.write_all(&frame).await?;
+    stream.flush().await
+}
+
+async fn read_frame<S: AsyncRead + Unpin>(stream: &mut S, message: &mut [u8]) -> io::Result<usize> {
+    let len = stream.read_u16().await? as usize;
+    stream.read_exact(&mut message[..len]).await?;
+    Ok(len)
+}
+
+/// Handshake failures, including a wrong key, read as invalid data
+fn noise_error(err: snow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+/// The keys of one tunneled connection, shared by both directions. Each
+/// direction counts its own nonces.
+pub struct Session {
+    role: Role,
+    state: Arc<StatelessTransportState>,
+}
+
+impl Session {
+    fn new(role: Role, noise: HandshakeState) -> io::Result<Session> {
+        let state = noise.into_stateless_transport_mode().map_err(noise_error)?;
+        Ok(Session {
+            role,
+            state: Arc::new(state),
+        })
+    }
+
+    /// Adds sealing or opening to the transforms of the direction read `from`
+    /// a side. Bytes are sealed after every other transform on the way into
+    /// the tunnel, and opened before them on the way out.
+    pub fn wrap(&self, chain: Option<Chain>, from: Side) -> Chain {
+        let chain = chain.unwrap_or_default();
+        let state = Arc::clone(&self.state);
+        if from == self.role.sealed() {
+            chain.first(Box::new(Open {
+                state,
+                nonce: 0,
+                pending: Vec::new(),
+            }))
+        } else {
+            chain.last(Box::new(Seal { state, nonce: 0 }))
+        }
+    }
+}
+
+/// Encrypts chunks into length-prefixed Noise messages
+struct Seal {
+    state: Arc<StatelessTransportState>,
+    nonce: u64,
+}
+
+impl Transform for Seal {
+    fn apply(&mut self, chunk: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        for piece in chunk.chunks(MAX_MESSAGE - TAG_LEN) {
+            let len = piece.len() + TAG_LEN;
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+            let at = out.len();
+            out.resize(at + len, 0);
+            self.state
+                .write_message(self.nonce, piece, &mut out[at..])
+                .map_err(noise_error)?;
+            self.nonce += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Decrypts the messages `Seal` wrote, failing on the first one that does not
+/// authenticate
+struct Open {
+    state: Arc<StatelessTransportState>,
+    nonce: u64,
+    /// The start of a message that has not fully arrived yet
+    pending: Vec<u8>,
+}
+
+impl Transform for Open {
+    fn apply(&mut self, chunk: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        self.pending.extend_from_slice(chunk);
+        let mut at = 0;
+        while self.pending.len() - at >= 2 {
+            let len = u16::from_be_bytes([self.pending[at], self.pending[at + 1]]) as usize;
+            if len < TAG_LEN {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Tunnel message is too short",
+                ));
+            }
+            if self.pending.len() - at < 2 + len {
+                break;
+            }
+            let start = out.len();
+            out.resize(start + len - TAG_LEN, 0);
+            self.state
+                .read_message(
+                    self.nonce,
+                    &self.pending[at + 2..at + 2 + len],
+                    &mut out[start..],
+                )
+                .map_err(noise_error)?;
+            self.nonce += 1;
+            at += 2 + len;
+        }
+        self.pending.drain(..at);
+        Ok(())
+    }
+
+    fn finish(&mut self, _out: &mut Vec<u8>) -> io::Result<()> {
+        if self.pending.is_empty() {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Truncated tunnel message",
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs the handshake over a pipe with a client and a server key
+    async fn handshake(
+        client: [u8; 32],
+        server: [u8; 32],
+    ) -> (io::Result<Session>, io::Result<Session>) {
+        let (mut near, mut far) = tokio::io::duplex(MAX_MESSAGE);
+        let client = Tunnel {
+            role: Role::Client,
+            key: client,
+        };
+        let server = Tunnel {
+            role: Role::Server,
+            key: server,
+        };
+        tokio::join!(client.handshake(&mut near), async {
+            let server = server.handshake(&mut far).await;
+            // A failed server closes the connection, as the proxy does
+            drop(far);
+            server
+        })
+    }
+
+    /// What the client seals towards the server, and what the server opens
+    async fn pair() -> (Chain, Chain) {
+        let (client, server) = handshake([7; 32], [7; 32]).await;
+        let (client, server) = (client.unwrap(), server.unwrap());
+        (
+            client.wrap(None, Side::Client),
+            server.wrap(None, Side::Client),
+        )
+    }
+
+    fn seal(seal: &mut Chain, chunks: &[&[u8]]) -> Vec<u8> {
+        let mut sealed = Vec::new();
+        for chunk in chunks {
+            seal.apply(chunk, &mut sealed).unwrap();
+        }
+        sealed
+    }
+
+    #[tokio::test]
+    async fn opens_messages_split_across_chunks() {
+        let (mut sealer, mut opener) = pair().await;
+        let large = vec![3; 2 * MAX_MESSAGE + 10];
+        let sealed = seal(&mut sealer, &[b"hello", &large, b"bye"]);
+
+        let mut opened = Vec::new();
+        for piece in sealed.chunks(1000) {
+            opener.apply(piece, &mut opened).unwrap();
+        }
+        opener.finish(&mut opened).unwrap();
+        assert_eq!(opened, [&b"hello"[..], &large, b"bye"].concat());
+    }
+
+    #[tokio::test]
+    async fn rejects_messages_shorter_than_their_tag() {
+        let (_, mut opener) = pair().await;
+        let short = (TAG_LEN as u16 - 1).to_be_bytes();
+        let err = opener.apply(&short, &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn rejects_tampered_messages() {
+        let (mut sealer, mut opener) = pair().await;
+        let mut sealed = seal(&mut sealer, &[b"hello"]);
+        sealed[4] ^= 1;
+        let err = opener.apply(&sealed, &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn detects_a_truncated_tail() {
+        let (mut sealer, mut opener) = pair().await;
+        let sealed = seal(&mut sealer, &[b"hello", b"world"]);
+        let mut opened = Vec::new();
+        opener
+            .apply(&sealed[..sealed.len() - 1], &mut opened)
+            .unwrap();
+        assert_eq!(opened, b"hello");
+        let err = opener.finish(&mut opened).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn a_wrong_key_fails_the_handshake() {
+        let (client, server) = handshake([7; 32], [8; 32]).await;
+        assert_eq!(server.err().unwrap().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(client.err().unwrap().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
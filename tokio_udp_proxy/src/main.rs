@@ -0,0 +1,211 @@
+use clap::Clap;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::net::UdpSocket;
+
+#[macro_use]
+extern crate lazy_static;
+
+/// A simple UDP proxy
+#[derive(Clap, Debug)]
+struct Args {
+    /// The address to listen on
+    #[clap(short, long, default_value = "127.0.0.1:20000")]
+    pub listen: String,
+    /// The address to forward datagrams to
+    #[clap(short, long, default_value = "127.0.0.1:20002")]
+    pub upstream: String,
+    /// Seconds without traffic from either side before a session is dropped
+    #[clap(short, long, default_value = "30")]
+    pub idle_timeout: u64,
+    /// Receive buffer size, which caps the size of a forwarded datagram
+    #[clap(short, long, default_value = "65536")]
+    pub buf_size: usize,
+    /// Most clients with a session at once; datagrams from new clients are
+    /// dropped while there are this many
+    #[clap(long, default_value = "10000")]
+    pub max_sessions: usize,
+    /// Worker threads. Datagrams from clients are all received on one task,
+    /// so more threads only run the upstream replies in parallel.
+    #[clap(short, long, default_value = "1")]
+    pub thread_count: usize,
+}
+
+lazy_static! {
+    static ref ARGS: Args = Args::parse();
+    static ref START: Instant = Instant::now();
+}
+
+/// A client's NAT-style mapping to its own upstream socket
+struct Session {
+    upstream: Arc<UdpSocket>,
+    /// Milliseconds since `START` at the last datagram in either direction
+    last_seen: AtomicU64,
+}
+
+impl Session {
+    fn touch(&self) {
+        self.last_seen.store(now_millis(), Ordering::Relaxed);
+    }
+
+    fn idle_for(&self) -> Duration {
+        Duration::from_millis(now_millis().saturating_sub(self.last_seen.load(Ordering::Relaxed)))
+    }
+}
+
+type Sessions = Arc<Mutex<HashMap<SocketAddr, Arc<Session>>>>;
+
+fn now_millis() -> u64 {
+    START.elapsed().as_millis() as u64
+}
+
+async fn listen() {
+    println!(
+        "listen={}, upstream={}, idle_timeout={}, buf_size={}, max_sessions={}",
+        &ARGS.listen, &ARGS.upstream, ARGS.idle_timeout, ARGS.buf_size, ARGS.max_sessions
+    );
+    let listener = Arc::new(
+        UdpSocket::bind(&ARGS.listen)
+            .await
+            .expect("Failed to bind to listen address"),
+    );
+    let upstream: SocketAddr = tokio::net::lookup_host(&ARGS.upstream)
+        .await
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .expect("Failed to resolve upstream address");
+    let sessions: Sessions = Arc::new(Mutex::new(HashMap::new()));
+
+    let mut buf: Vec<u8> = vec![0; ARGS.buf_size];
+    // Whether new clients are being turned away, so that is only logged once
+    let mut full = false;
+    loop {
+        let (n, client) = match listener.recv_from(&mut buf).await {
+            Ok(received) => received,
+            // ICMP errors from earlier sends surface here on some platforms
+            Err(_) => continue,
+        };
+
+        // Touched under the lock, so the session cannot expire before the
+        // datagram is sent
+        let (existing, count) = {
+            let sessions = sessions.lock().unwrap();
+            let existing = sessions.get(&client).cloned();
+            if let Some(session) = &existing {
+                session.touch();
+            }
+            (existing, sessions.len())
+        };
+        let session = match existing {
+            Some(session) => session,
+            None if count >= ARGS.max_sessions => {
+                if !full {
+                    println!("Too many sessions, dropping datagrams from new clients.");
+                    full = true;
+                }
+                continue;
+            }
+            None => match open_session(&listener, &sessions, client, upstream).await {
+                Ok(session) => {
+                    full = false;
+                    session
+                }
+                Err(_) => {
+                    println!("Failed to open upstream socket.");
+                    continue;
+                }
+            },
+        };
+
+        let _ = session.upstream.send(&buf[..n]).await;
+    }
+}
+
+async fn open_session(
+    listener: &Arc<UdpSocket>,
+    sessions: &Sessions,
+    client: SocketAddr,
+    upstream: SocketAddr,
+) -> std::io::Result<Arc<Session>> {
+    let local: SocketAddr = if upstream.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(upstream).await?;
+
+    let session = Arc::new(Session {
+        upstream: Arc::new(socket),
+        last_seen: AtomicU64::new(now_millis()),
+    });
+    sessions
+        .lock()
+        .unwrap()
+        .insert(client, Arc::clone(&session));
+
+    tokio::spawn(forward_replies(
+        Arc::clone(listener),
+        Arc::clone(sessions),
+        Arc::clone(&session),
+        client,
+    ));
+
+    Ok(session)
+}
+
+/// Relays upstream replies back to the client until the session goes idle,
+/// then removes it
+async fn forward_replies(
+    listener: Arc<UdpSocket>,
+    sessions: Sessions,
+    session: Arc<Session>,
+    client: SocketAddr,
+) {
+    let idle_timeout = Duration::from_secs(ARGS.idle_timeout);
+    let mut buf: Vec<u8> = vec![0; ARGS.buf_size];
+    loop {
+        match tokio::time::timeout(idle_timeout, session.upstream.recv(&mut buf)).await {
+            Ok(Ok(n)) => {
+                session.touch();
+                let _ = listener.send_to(&buf[..n], client).await;
+            }
+            Ok(Err(_)) => continue,
+            Err(_) => {
+                // The client may still be sending even if the upstream is
+                // quiet. Checked under the lock, so a datagram the receive
+                // loop is about to send either keeps the session alive or
+                // opens a new one.
+                let mut sessions = sessions.lock().unwrap();
+                if session.idle_for() >= idle_timeout {
+                    sessions.remove(&client);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn main() {
+    let runtime = if ARGS.thread_count > 1 {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(ARGS.thread_count)
+            .enable_all()
+            .build()
+            .unwrap()
+    } else {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    };
+
+    runtime.block_on(listen());
+}